@@ -0,0 +1,87 @@
+// Throughput of `mia create` (the walk, line counting and `add_to_zip`) on a synthetic tree
+//
+// cargo bench --bench add_to_zip
+// MIA_BENCH_FILES=50000 cargo bench --bench add_to_zip
+// MIA_BENCH_BIN=/path/to/other/mia cargo bench --bench add_to_zip   (compare against another build)
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::Instant;
+
+const DEFAULT_FILES: usize = 20_000;
+const FILES_PER_FOLDER: usize = 100;
+const RUNS: usize = 5;
+
+// A mix of known languages, files detected by name and unknown extensions
+const SAMPLES: [(&str, &str); 6] = [
+    ("rs", "// A comment\nfn main() {\n    let text = \"/* not a comment */\";\n    println!(\"{text}\");\n}\n"),
+    ("py", "#!/usr/bin/env python3\n# A comment\ndef main():\n    return \"# not a comment\"\n"),
+    ("ts", "/* A block\n   comment */\nexport const value: number = 42;\n\nconsole.log(value);\n"),
+    ("md", "# Title\n\nSome text.\n<!-- A comment -->\n"),
+    ("txt", "Plain text without a language.\n"),
+    ("json", "{\n  \"key\": \"value\"\n}\n"),
+];
+
+fn main() {
+    let files = env::var("MIA_BENCH_FILES")
+        .ok()
+        .and_then(|files| files.parse().ok())
+        .unwrap_or(DEFAULT_FILES);
+    let binary = env::var("MIA_BENCH_BIN")
+        .ok()
+        .filter(|binary| !binary.is_empty())
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from(env!("CARGO_BIN_EXE_mia")));
+
+    let root = env::temp_dir().join(format!("mia-bench-{}", std::process::id()));
+    let tree = root.join("tree");
+    let out = root.join("out");
+    // Keeps the user's config and languages files out of the measurement
+    let config = root.join("config");
+    let _ = fs::remove_dir_all(&root);
+    create_tree(&tree, files);
+    fs::create_dir_all(&out).unwrap();
+
+    println!("Benchmarking {binary:?} on {files} files");
+    let mut times = Vec::new();
+    for _ in 0..RUNS {
+        let start = Instant::now();
+        let status = Command::new(&binary)
+            .arg("create")
+            .arg(&tree)
+            .arg("bench")
+            .arg("-o")
+            .arg(&out)
+            .env("XDG_CONFIG_HOME", &config)
+            .env("HOME", &config)
+            .stdout(std::process::Stdio::null())
+            .status()
+            .expect("Couldn't run mia");
+        assert!(status.success(), "mia create failed");
+        times.push(start.elapsed());
+    }
+    fs::remove_dir_all(&root).unwrap();
+
+    times.sort();
+    let median = times[RUNS / 2];
+    println!(
+        "median {:?} (min {:?}, max {:?}), {:.0} files/s",
+        median,
+        times[0],
+        times[RUNS - 1],
+        files as f64 / median.as_secs_f64()
+    );
+}
+
+fn create_tree(tree: &Path, files: usize) {
+    for index in 0..files {
+        let folder = tree.join(format!("folder{}", index / FILES_PER_FOLDER));
+        if index % FILES_PER_FOLDER == 0 {
+            fs::create_dir_all(&folder).unwrap();
+        }
+        let (extension, content) = SAMPLES[index % SAMPLES.len()];
+        fs::write(folder.join(format!("file{index}.{extension}")), content.repeat(1 + index % 7)).unwrap();
+    }
+}
@@ -0,0 +1,497 @@
+// Archive Writer
+
+use crate::config::Config;
+use crate::error::Error;
+use crate::password::Password;
+use chrono::{Datelike, Timelike};
+use clap::ValueEnum;
+use flate2::write::GzEncoder;
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{Cursor, Write};
+use std::path::Path;
+use std::str::FromStr;
+use std::time::SystemTime;
+use xz2::write::XzEncoder;
+use zip::write::SimpleFileOptions;
+use zip::{AesMode, CompressionMethod, ZipWriter};
+
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum ArchiveFormat {
+    #[default]
+    Zip,
+    Tar,
+    TarGz,
+    TarXz,
+    TarZst,
+}
+
+impl ArchiveFormat {
+    // File extension (with leading dot) appended to the archive name
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ArchiveFormat::Zip => ".zip",
+            ArchiveFormat::Tar => ".tar",
+            ArchiveFormat::TarGz => ".tar.gz",
+            ArchiveFormat::TarXz => ".tar.xz",
+            ArchiveFormat::TarZst => ".tar.zst",
+        }
+    }
+}
+
+impl FromStr for ArchiveFormat {
+    type Err = Error;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        <ArchiveFormat as ValueEnum>::from_str(value, true).map_err(|_| {
+            Error::ConfigActionError(format!(
+                "Unknown format `{value}` (Expected zip, tar, tar-gz, tar-xz or tar-zst)"
+            ))
+        })
+    }
+}
+
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum Compression {
+    Stored,
+    #[default]
+    Deflate,
+    Bzip2,
+    Zstd,
+}
+
+impl From<Compression> for CompressionMethod {
+    fn from(compression: Compression) -> Self {
+        match compression {
+            Compression::Stored => CompressionMethod::Stored,
+            Compression::Deflate => CompressionMethod::Deflated,
+            Compression::Bzip2 => CompressionMethod::Bzip2,
+            Compression::Zstd => CompressionMethod::Zstd,
+        }
+    }
+}
+
+impl FromStr for Compression {
+    type Err = Error;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        <Compression as ValueEnum>::from_str(value, true).map_err(|_| {
+            Error::ConfigActionError(format!(
+                "Unknown compression `{value}` (Expected stored, deflate, bzip2 or zstd)"
+            ))
+        })
+    }
+}
+
+// Anything that entries can be written to. Each format gets its own implementation.
+// Entries are encoded by an `EntryEncoder` (on any thread) and then written in order.
+pub trait ArchiveWriter {
+    fn encoder(&self) -> EntryEncoder;
+    fn write_entry(&mut self, entry: EncodedEntry) -> Result<(), Error>;
+    fn write_symlink(&mut self, path: &str, target: &str, metadata: EntryMetadata) -> Result<(), Error>;
+    fn finish(&mut self) -> Result<(), Error>;
+}
+
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum SymlinkPolicy {
+    /// Archive what the link points to
+    #[default]
+    Follow,
+    /// Archive the link itself as a symlink entry
+    Store,
+    /// Leave links out
+    Skip,
+}
+
+impl FromStr for SymlinkPolicy {
+    type Err = Error;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        <SymlinkPolicy as ValueEnum>::from_str(value, true).map_err(|_| {
+            Error::ConfigActionError(format!(
+                "Unknown symlink policy `{value}` (Expected follow, store or skip)"
+            ))
+        })
+    }
+}
+
+// Per-entry metadata. `None` leaves the choice to the writer (time of archiving, `0o644`).
+#[derive(Debug, Default, Copy, Clone)]
+pub struct EntryMetadata {
+    // Seconds since the Unix epoch
+    pub mtime: Option<i64>,
+    pub mode: Option<u32>,
+}
+
+impl EntryMetadata {
+    // Fixed metadata for reproducible archives. Only the executable bit survives.
+    pub fn pinned(self, epoch: i64) -> Self {
+        let executable = self.mode.is_some_and(|mode| mode & 0o111 != 0);
+        EntryMetadata {
+            mtime: Some(epoch),
+            mode: Some(if executable { 0o755 } else { 0o644 }),
+        }
+    }
+}
+
+impl From<&std::fs::Metadata> for EntryMetadata {
+    fn from(metadata: &std::fs::Metadata) -> Self {
+        let mtime = metadata
+            .modified()
+            .ok()
+            .and_then(|time| time.duration_since(SystemTime::UNIX_EPOCH).ok())
+            .map(|duration| duration.as_secs() as i64);
+        #[cfg(unix)]
+        let mode = {
+            use std::os::unix::fs::PermissionsExt;
+            Some(metadata.permissions().mode() & 0o7777)
+        };
+        #[cfg(not(unix))]
+        let mode = None;
+        EntryMetadata { mtime, mode }
+    }
+}
+
+// Earliest time a zip entry can hold (1980-01-01 00:00:00 UTC)
+const ZIP_EPOCH: i64 = 315_532_800;
+
+// `SOURCE_DATE_EPOCH` if set (https://reproducible-builds.org/specs/source-date-epoch/),
+// otherwise the earliest zip time
+pub fn source_date_epoch() -> i64 {
+    std::env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|value| value.trim().parse::<i64>().ok())
+        .unwrap_or(ZIP_EPOCH)
+}
+
+// Zip entry times have no time zone and are read back as local time, so that's what is written. Reproducible
+// archives use UTC instead, so they don't depend on the machine they're made on.
+fn zip_time(seconds: i64, utc: bool) -> Option<zip::DateTime> {
+    let time = chrono::DateTime::from_timestamp(seconds.max(ZIP_EPOCH), 0)?;
+    let time = if utc { time.naive_utc() } else { time.with_timezone(&chrono::Local).naive_local() };
+    zip::DateTime::from_date_and_time(
+        u16::try_from(time.year()).ok()?,
+        time.month() as u8,
+        time.day() as u8,
+        time.hour() as u8,
+        time.minute() as u8,
+        time.second() as u8,
+    )
+    .ok()
+}
+
+pub enum EncodedEntry {
+    // Left for the writer to handle
+    Plain {
+        path: String,
+        content: Vec<u8>,
+        metadata: EntryMetadata,
+    },
+    // A single entry zip whose compressed data is copied as is into the output
+    Zip(Vec<u8>),
+}
+
+// The expensive, order independent part of writing an entry. Safe to run on a worker pool.
+#[derive(Clone)]
+pub enum EntryEncoder {
+    Plain,
+    Zip(ZipEntryOptions),
+}
+
+impl EntryEncoder {
+    pub fn encode(
+        &self,
+        path: &str,
+        content: Vec<u8>,
+        metadata: EntryMetadata,
+    ) -> Result<EncodedEntry, Error> {
+        match self {
+            EntryEncoder::Plain => Ok(EncodedEntry::Plain {
+                path: path.to_string(),
+                content,
+                metadata,
+            }),
+            EntryEncoder::Zip(options) => {
+                let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+                zip.start_file(path, options.options_for(path, metadata))?;
+                zip.write_all(&content)?;
+                Ok(EncodedEntry::Zip(zip.finish()?.into_inner()))
+            }
+        }
+    }
+}
+
+// Levels each compression accepts. Checked before the output file is created, so a bad level can't leave
+// a broken archive behind.
+pub fn check_level(config: &Config) -> Result<(), Error> {
+    let Some(level) = config.compression_level else { return Ok(()) };
+    let (name, range) = match (config.format, config.compression) {
+        (ArchiveFormat::Tar, _) | (ArchiveFormat::Zip, Compression::Stored) => {
+            return Err("This format isn't compressed, --level can't be used with it".into());
+        }
+        // Levels above 9 use Zopfli, which is much slower
+        (ArchiveFormat::Zip, Compression::Deflate) => ("Deflate", 1..=264),
+        (ArchiveFormat::Zip, Compression::Bzip2) => ("Bzip2", 1..=9),
+        (ArchiveFormat::Zip, Compression::Zstd) | (ArchiveFormat::TarZst, _) => ("Zstd", zstd::compression_level_range()),
+        (ArchiveFormat::TarGz, _) => ("Gzip", 0..=9),
+        (ArchiveFormat::TarXz, _) => ("Xz", 0..=9),
+    };
+    if !range.contains(&level) {
+        return Err(Error::Custom(format!(
+            "{name} compression levels go from {} to {}, {level} isn't one",
+            range.start(),
+            range.end()
+        )));
+    }
+    Ok(())
+}
+
+pub fn new_writer(
+    config: &Config,
+    file: File,
+    password: Option<Password>,
+) -> Result<Box<dyn ArchiveWriter>, Error> {
+    if password.is_some() && config.format != ArchiveFormat::Zip {
+        return Err("Encryption is only supported for zip archives".into());
+    }
+    check_level(config)?;
+    let level = config.compression_level;
+    let writer: Box<dyn ArchiveWriter> = match config.format {
+        ArchiveFormat::Zip => Box::new(ZipArchive {
+            zip: Some(ZipWriter::new(file)),
+            options: ZipEntryOptions {
+                compression: config.compression,
+                level: config.compression_level,
+                stored_extensions: config.stored_extensions.clone(),
+                reproducible: config.reproducible,
+            },
+            password,
+        }),
+        ArchiveFormat::Tar => Box::new(TarArchive::new(TarSink::Plain(file))),
+        ArchiveFormat::TarGz => Box::new(TarArchive::new(TarSink::Gz(GzEncoder::new(
+            file,
+            level.map_or(flate2::Compression::default(), |level| flate2::Compression::new(level as u32)),
+        )))),
+        ArchiveFormat::TarXz => Box::new(TarArchive::new(TarSink::Xz(XzEncoder::new(
+            file,
+            level.map_or(6, |level| level as u32),
+        )))),
+        ArchiveFormat::TarZst => Box::new(TarArchive::new(TarSink::Zst(
+            zstd::Encoder::new(file, level.unwrap_or(0))?,
+        ))),
+    };
+    Ok(writer)
+}
+
+#[derive(Clone)]
+pub struct ZipEntryOptions {
+    compression: Compression,
+    level: Option<i32>,
+    // Already compressed files gain nothing from another pass, so they're stored as is
+    stored_extensions: Vec<String>,
+    reproducible: bool,
+}
+
+impl ZipEntryOptions {
+    fn options_for(&self, path: &str, metadata: EntryMetadata) -> SimpleFileOptions {
+        let mut options = SimpleFileOptions::default();
+        if let Some(time) = metadata.mtime.and_then(|mtime| zip_time(mtime, self.reproducible)) {
+            options = options.last_modified_time(time);
+        }
+        if let Some(mode) = metadata.mode {
+            options = options.unix_permissions(mode);
+        }
+
+        let extension = Path::new(path)
+            .extension()
+            .map(|extension| extension.to_string_lossy().to_ascii_lowercase())
+            .unwrap_or_default();
+        if self.compression == Compression::Stored || self.stored_extensions.contains(&extension) {
+            return options.compression_method(CompressionMethod::Stored);
+        }
+        options
+            .compression_method(self.compression.into())
+            .compression_level(self.level.map(i64::from))
+    }
+}
+
+struct ZipArchive {
+    // Taken out on finish, since `ZipWriter::finish` requires ownership
+    zip: Option<ZipWriter<File>>,
+    options: ZipEntryOptions,
+    // Entries are written as WinZip AES-256 when set
+    password: Option<Password>,
+}
+
+impl ArchiveWriter for ZipArchive {
+    fn encoder(&self) -> EntryEncoder {
+        // AES entries can't be raw copied, so encryption happens while writing
+        if self.password.is_some() {
+            EntryEncoder::Plain
+        } else {
+            EntryEncoder::Zip(self.options.clone())
+        }
+    }
+
+    fn write_entry(&mut self, entry: EncodedEntry) -> Result<(), Error> {
+        let zip = self
+            .zip
+            .as_mut()
+            .ok_or_else(|| Error::from("Archive has already been finished"))?;
+        match entry {
+            EncodedEntry::Plain {
+                path,
+                content,
+                metadata,
+            } => {
+                let options = self.options.options_for(&path, metadata);
+                match &self.password {
+                    Some(password) => zip.start_file(
+                        path,
+                        options.with_aes_encryption(AesMode::Aes256, password.as_str()),
+                    )?,
+                    None => zip.start_file(path, options)?,
+                }
+                zip.write_all(&content)?;
+            }
+            EncodedEntry::Zip(bytes) => {
+                let mut single = zip::ZipArchive::new(Cursor::new(bytes))?;
+                zip.raw_copy_file(single.by_index_raw(0)?)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Link targets are tiny and never encrypted, so they're always stored
+    fn write_symlink(&mut self, path: &str, target: &str, metadata: EntryMetadata) -> Result<(), Error> {
+        let zip = self
+            .zip
+            .as_mut()
+            .ok_or_else(|| Error::from("Archive has already been finished"))?;
+        let mut options = SimpleFileOptions::default()
+            .compression_method(CompressionMethod::Stored)
+            .unix_permissions(metadata.mode.unwrap_or(0o777));
+        if let Some(time) = metadata.mtime.and_then(|mtime| zip_time(mtime, self.options.reproducible)) {
+            options = options.last_modified_time(time);
+        }
+        zip.add_symlink(path, target, options)?;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), Error> {
+        if let Some(zip) = self.zip.take() {
+            zip.finish().map_err(Error::ZipFileFail)?;
+        }
+        Ok(())
+    }
+}
+
+// Output stream under the tar builder. Compressed variants need to be finished explicitly.
+enum TarSink {
+    Plain(File),
+    Gz(GzEncoder<File>),
+    Xz(XzEncoder<File>),
+    Zst(zstd::Encoder<'static, File>),
+}
+
+impl TarSink {
+    fn finish(self) -> std::io::Result<()> {
+        match self {
+            TarSink::Plain(mut file) => file.flush(),
+            TarSink::Gz(encoder) => encoder.finish().map(|_| ()),
+            TarSink::Xz(encoder) => encoder.finish().map(|_| ()),
+            TarSink::Zst(encoder) => encoder.finish().map(|_| ()),
+        }
+    }
+}
+
+impl Write for TarSink {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            TarSink::Plain(file) => file.write(buf),
+            TarSink::Gz(encoder) => encoder.write(buf),
+            TarSink::Xz(encoder) => encoder.write(buf),
+            TarSink::Zst(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            TarSink::Plain(file) => file.flush(),
+            TarSink::Gz(encoder) => encoder.flush(),
+            TarSink::Xz(encoder) => encoder.flush(),
+            TarSink::Zst(encoder) => encoder.flush(),
+        }
+    }
+}
+
+struct TarArchive {
+    // Taken out on finish, since finishing the sink requires ownership
+    builder: Option<tar::Builder<TarSink>>,
+    mtime: u64,
+}
+
+impl TarArchive {
+    fn new(sink: TarSink) -> Self {
+        let mtime = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|duration| duration.as_secs())
+            .unwrap_or_default();
+        TarArchive {
+            builder: Some(tar::Builder::new(sink)),
+            mtime,
+        }
+    }
+
+    fn builder(&mut self) -> Result<&mut tar::Builder<TarSink>, Error> {
+        self.builder
+            .as_mut()
+            .ok_or_else(|| "Archive has already been finished".into())
+    }
+}
+
+impl ArchiveWriter for TarArchive {
+    // The compression of tar formats is a single stream, so there's nothing to do up front
+    fn encoder(&self) -> EntryEncoder {
+        EntryEncoder::Plain
+    }
+
+    fn write_entry(&mut self, entry: EncodedEntry) -> Result<(), Error> {
+        let EncodedEntry::Plain {
+            path,
+            content,
+            metadata,
+        } = entry
+        else {
+            return Err("Tar archives only accept plain entries".into());
+        };
+        // A fresh GNU header leaves owner ids and names empty, so nothing host specific ends up in it
+        let mut header = tar::Header::new_gnu();
+        header.set_size(content.len() as u64);
+        header.set_mode(metadata.mode.unwrap_or(0o644));
+        header.set_mtime(metadata.mtime.map(|mtime| mtime.max(0) as u64).unwrap_or(self.mtime));
+        header.set_entry_type(tar::EntryType::Regular);
+        self.builder()?.append_data(&mut header, path, content.as_slice())?;
+        Ok(())
+    }
+
+    fn write_symlink(&mut self, path: &str, target: &str, metadata: EntryMetadata) -> Result<(), Error> {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(0);
+        header.set_mode(metadata.mode.unwrap_or(0o777));
+        header.set_mtime(metadata.mtime.map(|mtime| mtime.max(0) as u64).unwrap_or(self.mtime));
+        header.set_entry_type(tar::EntryType::Symlink);
+        self.builder()?.append_link(&mut header, path, target)?;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), Error> {
+        if let Some(builder) = self.builder.take() {
+            builder.into_inner()?.finish()?;
+        }
+        Ok(())
+    }
+}
@@ -1,8 +1,14 @@
 // Command Line Interface Manager
 // Author: Derek Blaney
 
+use crate::archive::{ArchiveFormat, Compression, SymlinkPolicy};
+use crate::extract::OverwriteMode;
+use crate::manifest::ManifestFormat;
+use crate::password::PasswordArgs;
+use crate::report::ReportFormat;
 use clap::{Parser, Subcommand};
 use std::ffi::OsString;
+use std::path::PathBuf;
 
 #[derive(Debug, Parser)]
 #[command(author, version, about)]
@@ -24,11 +30,11 @@ pub enum Zip {
         #[arg(short, long)]
         verbose: bool,
 
-        /// Exclude certain files, extensions, folders
+        /// Exclude certain files, extensions, folders or glob patterns
         #[arg(short, long)]
         exclude: Vec<String>,
 
-        /// Include certain files, extensions, folders
+        /// Include certain files, extensions, folders or glob patterns (Overrides exclusions)
         #[arg(short, long)]
         include: Vec<String>,
 
@@ -39,13 +45,161 @@ pub enum Zip {
         /// Uses default output (Same directory as input) instead of config value
         #[arg(short, long)]
         default_out: bool,
+
+        /// Archive format, overrides the config value
+        #[arg(short, long, value_enum)]
+        format: Option<ArchiveFormat>,
+
+        /// Compression method for zip entries, overrides the config value
+        #[arg(short, long, value_enum)]
+        compression: Option<Compression>,
+
+        /// Compression level, overrides the config value.
+        /// Zip: deflate 1-9 (10-264 use Zopfli), bzip2 1-9, zstd up to 22 (negative is faster).
+        /// tar-gz and tar-xz: 0-9, tar-zst: same as zstd. Not accepted by tar or stored zips.
+        #[arg(short, long, allow_negative_numbers = true)]
+        level: Option<i32>,
+
+        /// Don't read .gitignore, .git/info/exclude and .miaignore files
+        #[arg(long)]
+        no_ignore: bool,
+
+        /// What to do with symbolic links, overrides the config value
+        #[arg(long, value_enum)]
+        symlinks: Option<SymlinkPolicy>,
+
+        /// Byte-identical output for the same input: sorted entries, timestamps pinned to
+        /// SOURCE_DATE_EPOCH (or 1980-01-01) and normalised permissions
+        #[arg(long)]
+        reproducible: bool,
+
+        /// Encrypt zip entries with AES-256, asks for a password unless one is given.
+        /// Implied by --password-env and --password-file.
+        #[arg(long)]
+        encrypt: bool,
+
+        #[command(flatten)]
+        password: PasswordArgs,
+
+        /// Write a report of every included and excluded path, replaces the usual output
+        /// unless --report-file is given
+        #[arg(long, value_enum)]
+        report: Option<ReportFormat>,
+
+        /// Write the report to this file instead of the terminal
+        #[arg(long, requires = "report")]
+        report_file: Option<PathBuf>,
+
+        /// Show what would be archived and why entries are excluded, without creating the archive
+        #[arg(long)]
+        dry_run: bool,
+
+        /// Add a manifest with the SHA-256 of every file, checked by `mia verify`
+        #[arg(long, value_enum, num_args = 0..=1, default_missing_value = "json")]
+        manifest: Option<ManifestFormat>,
+
+        /// Apply a named profile from the config or a .mia.toml, flags still override it
+        #[arg(short, long)]
+        profile: Option<String>,
+    },
+    /// Unpack a zip archive
+    Extract {
+        /// The archive location
+        archive: PathBuf,
+        /// The destination folder, defaults to a folder named after the archive
+        dest: Option<PathBuf>,
+
+        // Flags
+
+        #[arg(short, long)]
+        verbose: bool,
+
+        /// Exclude certain files, extensions, folders or glob patterns
+        #[arg(short, long)]
+        exclude: Vec<String>,
+
+        /// Include certain files, extensions, folders or glob patterns (Overrides exclusions)
+        #[arg(short, long)]
+        include: Vec<String>,
+
+        /// What to do when a file already exists
+        #[arg(long, value_enum, default_value_t)]
+        overwrite: OverwriteMode,
+
+        #[command(flatten)]
+        password: PasswordArgs,
+    },
+    /// Show the entries and language statistics of a zip archive without extracting it
+    List {
+        /// The archive location
+        archive: PathBuf,
+
+        #[command(flatten)]
+        password: PasswordArgs,
+    },
+    /// Check that every entry of a zip archive can be read back and matches its manifest
+    Verify {
+        /// The archive location
+        archive: PathBuf,
+
+        #[command(flatten)]
+        password: PasswordArgs,
+    },
+    /// Language, folder and file size statistics of a folder, without creating an archive
+    Stats {
+        /// The folder location
+        location: OsString,
+
+        // Flags
+
+        /// Exclude certain files, extensions, folders or glob patterns
+        #[arg(short, long)]
+        exclude: Vec<String>,
+
+        /// Include certain files, extensions, folders or glob patterns (Overrides exclusions)
+        #[arg(short, long)]
+        include: Vec<String>,
+
+        /// Don't read .gitignore, .git/info/exclude and .miaignore files
+        #[arg(long)]
+        no_ignore: bool,
+
+        /// Number of largest files to show
+        #[arg(short, long, default_value_t = 10)]
+        top: usize,
+
+        /// How many folder levels the tree shows
+        #[arg(long, default_value_t = 2)]
+        depth: usize,
     },
     Config {
         #[command(subcommand)]
         action: ConfigAction,
     },
     Update {
-        version: Option<String>
+        version: Option<String>,
+
+        /// Restore the binary the last update replaced
+        #[arg(long, conflicts_with_all = ["version", "list_installed"])]
+        rollback: bool,
+
+        /// List the retained binaries `--rollback` can restore
+        #[arg(long, conflicts_with = "version")]
+        list_installed: bool,
+
+        /// Only tell whether a newer release is available
+        #[arg(long, conflicts_with_all = ["version", "rollback", "list_installed"])]
+        check: bool,
+    },
+    /// List the available releases, newest first
+    Releases {
+        /// How many releases to show
+        #[arg(short = 'n', long, default_value_t = 10)]
+        limit: usize,
+
+        /// Show the full release notes instead of their first line
+        #[arg(long)]
+        notes: bool,
     },
     Version
 }
@@ -55,5 +209,64 @@ pub enum ConfigAction {
     Set { key: OsString, value: OsString },
     Add { key: OsString, value: OsString },
     Remove { key: OsString, value: OsString },
-    List,
+    List {
+        /// Show the values used in the current folder, with the config file or env var each one comes from
+        #[arg(long)]
+        effective: bool,
+
+        /// Apply a profile to the effective values
+        #[arg(long, requires = "effective")]
+        profile: Option<String>,
+    },
+    /// Manage named profiles used by `mia create --profile`
+    Profile {
+        #[command(subcommand)]
+        action: ProfileAction,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum ProfileAction {
+    /// Add a profile, replacing any profile with the same name
+    Add {
+        name: String,
+
+        /// Naming scheme for the archive
+        #[arg(long)]
+        naming: Option<String>,
+
+        /// Output directory
+        #[arg(short, long)]
+        out: Option<String>,
+
+        /// Archive format
+        #[arg(short, long, value_enum)]
+        format: Option<ArchiveFormat>,
+
+        /// Compression method for zip entries
+        #[arg(short, long, value_enum)]
+        compression: Option<Compression>,
+
+        /// Compression level.
+        /// Zip: deflate 1-9 (10-264 use Zopfli), bzip2 1-9, zstd up to 22 (negative is faster).
+        /// tar-gz and tar-xz: 0-9, tar-zst: same as zstd. Not accepted by tar or stored zips.
+        #[arg(short, long, allow_negative_numbers = true)]
+        level: Option<i32>,
+
+        /// File name added to the blacklist
+        #[arg(long)]
+        file_name: Vec<String>,
+
+        /// Folder name added to the blacklist
+        #[arg(long)]
+        folder_name: Vec<String>,
+
+        /// File extension added to the blacklist (Do not include the `.`)
+        #[arg(long)]
+        extension: Vec<String>,
+    },
+    /// Remove a profile from the config
+    Remove { name: String },
+    /// Show a profile, or every profile available in the current folder
+    Show { name: Option<String> },
 }
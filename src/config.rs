@@ -1,10 +1,17 @@
 // Config Manager
 // Author: Derek Blaney
 
+use crate::archive::{ArchiveFormat, Compression, SymlinkPolicy};
 use crate::error::Error;
+use crate::release::Channel;
+use clap::ValueEnum;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::path::{Path, PathBuf};
 
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Config {
     pub naming: String, // Follows format `:name` by default
     pub blacklisted_file_names: Vec<String>,
@@ -12,6 +19,111 @@ pub struct Config {
     pub blacklisted_file_extensions: Vec<String>,
 
     pub output_dir: Option<String>,
+    #[serde(default)]
+    pub format: ArchiveFormat,
+    #[serde(default)]
+    pub compression: Compression,
+    #[serde(default)]
+    pub compression_level: Option<i32>,
+    #[serde(default = "default_stored_extensions")]
+    pub stored_extensions: Vec<String>,
+    // Honour .gitignore, .git/info/exclude and .miaignore files
+    #[serde(default = "default_true")]
+    pub use_ignore_files: bool,
+    // Pinned timestamps and permissions, so the same tree always gives the same bytes
+    #[serde(default)]
+    pub reproducible: bool,
+    #[serde(default)]
+    pub symlinks: SymlinkPolicy,
+    // Let vim and emacs modelines pick the language of a file
+    #[serde(default = "default_true")]
+    pub detect_modelines: bool,
+    // Binaries kept by `mia update` for `--rollback`
+    #[serde(default = "default_keep_versions")]
+    pub keep_versions: usize,
+    // Whether `mia update` and `mia releases` consider prereleases
+    #[serde(default)]
+    pub channel: Channel,
+    // Where `mia update` looks for releases, see `source::parse`
+    #[serde(default)]
+    pub release_source: Option<String>,
+    // Named sets of values for `mia create --profile`, kept last so they're written as TOML tables
+    #[serde(default)]
+    pub profiles: BTreeMap<String, Profile>,
+}
+
+// Values applied over the config by `--profile <name>`, unset ones are left alone
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Profile {
+    pub naming: Option<String>,
+    pub output_dir: Option<String>,
+    pub format: Option<ArchiveFormat>,
+    pub compression: Option<Compression>,
+    pub compression_level: Option<i32>,
+    // Added to the config blacklists
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub blacklisted_file_names: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub blacklisted_folder_names: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub blacklisted_file_extensions: Vec<String>,
+}
+
+impl Profile {
+    // The values the profile sets, as shown by `mia config profile show`
+    pub fn entries(&self) -> Vec<(&'static str, String)> {
+        let mut entries = Vec::new();
+        if let Some(naming) = &self.naming {
+            entries.push(("naming", naming.clone()));
+        }
+        if let Some(output_dir) = &self.output_dir {
+            entries.push(("output_dir", output_dir.clone()));
+        }
+        if let Some(format) = self.format {
+            entries.push(("format", format!("{format:?}")));
+        }
+        if let Some(compression) = self.compression {
+            entries.push(("compression", format!("{compression:?}")));
+        }
+        if let Some(level) = self.compression_level {
+            entries.push(("compression_level", level.to_string()));
+        }
+        for (key, values) in [
+            ("blacklisted_file_names", &self.blacklisted_file_names),
+            ("blacklisted_folder_names", &self.blacklisted_folder_names),
+            ("blacklisted_file_extensions", &self.blacklisted_file_extensions),
+        ] {
+            if !values.is_empty() {
+                entries.push((key, format!("{values:?}")));
+            }
+        }
+        entries
+    }
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_keep_versions() -> usize {
+    3
+}
+
+fn default_stored_extensions() -> Vec<String> {
+    [
+        "png", "jpg", "jpeg", "gif", "webp", "ico", "zip", "gz", "tgz", "xz", "zst", "bz2", "7z",
+        "rar", "jar", "mp3", "ogg", "flac", "mp4", "mkv", "mov", "avi", "webm", "woff", "woff2",
+    ]
+    .iter()
+    .map(|extension| extension.to_string())
+    .collect()
+}
+
+// Every kind of archive mia writes, so earlier outputs aren't packed into the next one
+fn default_blacklisted_file_extensions() -> Vec<String> {
+    let archives = ArchiveFormat::value_variants().iter().map(|format| format.extension().trim_start_matches('.'));
+    archives.chain(["pdf"]).map(String::from).collect()
 }
 
 impl Default for Config {
@@ -26,8 +138,20 @@ impl Default for Config {
                 ".idea".to_string(),
                 ".vs".to_string(),
             ],
-            blacklisted_file_extensions: vec!["zip".to_string(), "pdf".to_string()],
+            blacklisted_file_extensions: default_blacklisted_file_extensions(),
             output_dir: None,
+            format: ArchiveFormat::default(),
+            compression: Compression::default(),
+            compression_level: None,
+            stored_extensions: default_stored_extensions(),
+            use_ignore_files: true,
+            reproducible: false,
+            symlinks: SymlinkPolicy::default(),
+            detect_modelines: true,
+            keep_versions: default_keep_versions(),
+            channel: Channel::default(),
+            release_source: None,
+            profiles: BTreeMap::new(),
         }
     }
 }
@@ -41,6 +165,48 @@ impl Config {
             "output_dir" => {
                 self.output_dir = Some(value);
             }
+            "format" => {
+                self.format = value.parse()?;
+            }
+            "compression" => {
+                self.compression = value.parse()?;
+            }
+            "use_ignore_files" => {
+                self.use_ignore_files = value.parse().map_err(|_| {
+                    Error::ConfigActionError("Value must be `true` or `false`".to_string())
+                })?;
+            }
+            "symlinks" => {
+                self.symlinks = value.parse()?;
+            }
+            "reproducible" => {
+                self.reproducible = value.parse().map_err(|_| {
+                    Error::ConfigActionError("Value must be `true` or `false`".to_string())
+                })?;
+            }
+            "detect_modelines" => {
+                self.detect_modelines = value.parse().map_err(|_| {
+                    Error::ConfigActionError("Value must be `true` or `false`".to_string())
+                })?;
+            }
+            "keep_versions" => {
+                self.keep_versions = value.parse().map_err(|_| {
+                    Error::ConfigActionError("Value must be a whole number".to_string())
+                })?;
+            }
+            "channel" => {
+                self.channel = value.parse()?;
+            }
+            "release_source" => {
+                crate::source::parse(&value)?;
+                self.release_source = Some(value);
+            }
+            "compression_level" => {
+                let level = value.parse().map_err(|_| {
+                    Error::ConfigActionError("Compression level must be a number".to_string())
+                })?;
+                self.compression_level = Some(level);
+            }
             _ => {
                 return Err(Error::ConfigActionError(
                     "Can't set key to value".to_string(),
@@ -61,6 +227,9 @@ impl Config {
             "blacklisted_file_extensions" => {
                 self.blacklisted_file_extensions.push(value);
             }
+            "stored_extensions" => {
+                self.stored_extensions.push(value);
+            }
             _ => {
                 return Err(Error::ConfigActionError("Couldn't find key".to_string()));
             }
@@ -82,6 +251,10 @@ impl Config {
                 let index = find_position_in_vec(&self.blacklisted_file_extensions, value)?;
                 self.blacklisted_file_extensions.remove(index);
             }
+            "stored_extensions" => {
+                let index = find_position_in_vec(&self.stored_extensions, value)?;
+                self.stored_extensions.remove(index);
+            }
             _ => {
                 return Err(Error::ConfigActionError("Couldn't find key".to_string()));
             }
@@ -90,6 +263,238 @@ impl Config {
     }
 }
 
+// Read from the target folder and its parents, holds config values and `[[languages]]`
+pub const PROJECT_CONFIG_FILE: &str = ".mia.toml";
+
+// Keys set with `mia config set`, or a single value in a project file / env var
+const VALUE_KEYS: [&str; 12] = [
+    "naming",
+    "output_dir",
+    "format",
+    "compression",
+    "compression_level",
+    "use_ignore_files",
+    "symlinks",
+    "reproducible",
+    "detect_modelines",
+    "keep_versions",
+    "channel",
+    "release_source",
+];
+// Keys edited with `mia config add/remove`, a list in a project file or comma separated in an env var
+const LIST_KEYS: [&str; 4] = [
+    "blacklisted_file_names",
+    "blacklisted_folder_names",
+    "blacklisted_file_extensions",
+    "stored_extensions",
+];
+// Project file keys that aren't config values
+const PROJECT_ONLY_KEYS: [&str; 2] = ["languages", "profiles"];
+// Keys about mia itself rather than a project. `mia update` doesn't read project files, so they're refused there.
+const GLOBAL_ONLY_KEYS: [&str; 3] = ["keep_versions", "channel", "release_source"];
+
+// Where the effective value of a key comes from
+#[derive(Debug, Clone)]
+pub enum Source {
+    Global,
+    // The variable's name
+    Env(String),
+    Project(PathBuf),
+    // The profile's name
+    Profile(String),
+}
+
+impl Display for Source {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Source::Global => write!(f, "global config"),
+            Source::Env(variable) => write!(f, "env {variable}"),
+            Source::Project(file) => write!(f, "{}", file.display()),
+            Source::Profile(name) => write!(f, "profile {name}"),
+        }
+    }
+}
+
+// Source of every key, keys missing from it come from the global config
+pub type Sources = BTreeMap<&'static str, Source>;
+
+impl Config {
+    // The global config with `MIA_<KEY>` env vars, then the `.mia.toml` files of `dir` and its
+    // parents (outermost first) on top. Command line flags are applied over the result by the caller.
+    pub fn layered(self, dir: &Path) -> Result<(Config, Sources), Error> {
+        let (mut config, mut sources) = self.with_env()?;
+        for file in project_config_files(dir) {
+            config.apply_project_file(&file, &mut sources)?;
+        }
+        Ok((config, sources))
+    }
+
+    // The global config with `MIA_<KEY>` env vars on top
+    pub fn with_env(mut self) -> Result<(Config, Sources), Error> {
+        let mut sources = Sources::new();
+        for key in VALUE_KEYS.iter().chain(LIST_KEYS.iter()) {
+            let variable = format!("MIA_{}", key.to_ascii_uppercase());
+            let Ok(value) = std::env::var(&variable) else { continue };
+            if LIST_KEYS.contains(key) {
+                let values = value.split(',').map(str::trim).filter(|value| !value.is_empty());
+                self.set_list(key, values.map(String::from).collect())?;
+            } else {
+                self.set_key(key, value)
+                    .map_err(|error| Error::ConfigActionError(format!("{variable}: {error:?}")))?;
+            }
+            sources.insert(key, Source::Env(variable));
+        }
+        Ok((self, sources))
+    }
+
+    // One `.mia.toml`, on top of the current values
+    fn apply_project_file(&mut self, file: &Path, sources: &mut Sources) -> Result<(), Error> {
+        let text = fs::read_to_string(file)?;
+        let table: toml::Table = toml::from_str(&text)
+            .map_err(|error| Error::ConfigActionError(format!("{}: {error}", file.display())))?;
+        for (key, value) in table {
+            if key == "profiles" {
+                // Project profiles replace global ones with the same name
+                let profiles: BTreeMap<String, Profile> = value
+                    .try_into()
+                    .map_err(|error| Error::ConfigActionError(format!("{}: {error}", file.display())))?;
+                self.profiles.extend(profiles);
+                continue;
+            }
+            if PROJECT_ONLY_KEYS.contains(&key.as_str()) {
+                continue;
+            }
+            if GLOBAL_ONLY_KEYS.contains(&key.as_str()) {
+                return Err(Error::ConfigActionError(format!(
+                    "{}: `{key}` can only be set in the global config",
+                    file.display()
+                )));
+            }
+            let known = VALUE_KEYS.iter().chain(LIST_KEYS.iter()).find(|known| **known == key);
+            let Some(&key) = known else {
+                return Err(Error::ConfigActionError(format!("{}: unknown key `{key}`", file.display())));
+            };
+            self.set_toml(key, value)
+                .map_err(|error| Error::ConfigActionError(format!("{}: {error:?}", file.display())))?;
+            sources.insert(key, Source::Project(file.to_owned()));
+        }
+        Ok(())
+    }
+
+    // Applied after `layered`, before command line flags
+    pub fn apply_profile(&mut self, name: &str, sources: &mut Sources) -> Result<(), Error> {
+        let Some(profile) = self.profiles.get(name).cloned() else {
+            return Err(Error::ConfigActionError(format!("Couldn't find profile `{name}`")));
+        };
+        let source = Source::Profile(name.to_string());
+        if let Some(naming) = profile.naming {
+            self.naming = naming;
+            sources.insert("naming", source.clone());
+        }
+        if let Some(output_dir) = profile.output_dir {
+            self.output_dir = Some(output_dir);
+            sources.insert("output_dir", source.clone());
+        }
+        if let Some(format) = profile.format {
+            self.format = format;
+            sources.insert("format", source.clone());
+        }
+        if let Some(compression) = profile.compression {
+            self.compression = compression;
+            sources.insert("compression", source.clone());
+        }
+        if let Some(level) = profile.compression_level {
+            self.compression_level = Some(level);
+            sources.insert("compression_level", source.clone());
+        }
+        for (key, list, values) in [
+            ("blacklisted_file_names", &mut self.blacklisted_file_names, profile.blacklisted_file_names),
+            ("blacklisted_folder_names", &mut self.blacklisted_folder_names, profile.blacklisted_folder_names),
+            ("blacklisted_file_extensions", &mut self.blacklisted_file_extensions, profile.blacklisted_file_extensions),
+        ] {
+            if values.is_empty() {
+                continue;
+            }
+            for value in values {
+                if !list.contains(&value) {
+                    list.push(value);
+                }
+            }
+            sources.insert(key, source.clone());
+        }
+        Ok(())
+    }
+
+    fn set_toml(&mut self, key: &str, value: toml::Value) -> Result<(), Error> {
+        if LIST_KEYS.contains(&key) {
+            let toml::Value::Array(values) = value else {
+                return Err(Error::ConfigActionError(format!("`{key}` must be a list")));
+            };
+            let values = values
+                .into_iter()
+                .map(|value| match value {
+                    toml::Value::String(value) => Ok(value),
+                    _ => Err(Error::ConfigActionError(format!("`{key}` must be a list of strings"))),
+                })
+                .collect::<Result<Vec<_>, Error>>()?;
+            return self.set_list(key, values);
+        }
+        let value = match value {
+            toml::Value::String(value) => value,
+            toml::Value::Integer(value) => value.to_string(),
+            toml::Value::Boolean(value) => value.to_string(),
+            _ => return Err(Error::ConfigActionError(format!("`{key}` must be a single value"))),
+        };
+        self.set_key(key, value)
+    }
+
+    fn set_list(&mut self, key: &str, values: Vec<String>) -> Result<(), Error> {
+        match key {
+            "blacklisted_file_names" => self.blacklisted_file_names = values,
+            "blacklisted_folder_names" => self.blacklisted_folder_names = values,
+            "blacklisted_file_extensions" => self.blacklisted_file_extensions = values,
+            "stored_extensions" => self.stored_extensions = values,
+            _ => return Err(Error::ConfigActionError("Couldn't find key".to_string())),
+        }
+        Ok(())
+    }
+
+    // Every key with its value as shown by `mia config list --effective`
+    pub fn entries(&self) -> Vec<(&'static str, String)> {
+        let list = |values: &Vec<String>| format!("{values:?}");
+        vec![
+            ("naming", self.naming.clone()),
+            ("output_dir", self.output_dir.clone().unwrap_or("Not set".to_string())),
+            ("format", format!("{:?}", self.format)),
+            ("compression", format!("{:?}", self.compression)),
+            ("compression_level", self.compression_level.map(|level| level.to_string()).unwrap_or("Default".to_string())),
+            ("use_ignore_files", self.use_ignore_files.to_string()),
+            ("symlinks", format!("{:?}", self.symlinks)),
+            ("reproducible", self.reproducible.to_string()),
+            ("detect_modelines", self.detect_modelines.to_string()),
+            ("keep_versions", self.keep_versions.to_string()),
+            ("channel", format!("{:?}", self.channel)),
+            ("release_source", self.release_source.clone().unwrap_or(crate::source::DEFAULT_SOURCE.to_string())),
+            ("blacklisted_file_names", list(&self.blacklisted_file_names)),
+            ("blacklisted_folder_names", list(&self.blacklisted_folder_names)),
+            ("blacklisted_file_extensions", list(&self.blacklisted_file_extensions)),
+            ("stored_extensions", list(&self.stored_extensions)),
+        ]
+    }
+}
+
+// `.mia.toml` files of `dir` and its parents, outermost first
+pub fn project_config_files(dir: &Path) -> Vec<PathBuf> {
+    let dir = dir.canonicalize().unwrap_or_else(|_| dir.to_path_buf());
+    let mut files: Vec<PathBuf> = dir
+        .ancestors()
+        .map(|dir| dir.join(PROJECT_CONFIG_FILE))
+        .filter(|file| file.is_file())
+        .collect();
+    files.reverse();
+    files
+}
+
 fn find_position_in_vec(vec: &[String], value: String) -> Result<usize, Error> {
     let index = vec
         .iter()
@@ -99,3 +504,90 @@ fn find_position_in_vec(vec: &[String], value: String) -> Result<usize, Error> {
         None => Err(Error::ConfigActionError("Couldn't find value in vec".to_string()))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("mia-config-{}-{name}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    // The only test that sets `MIA_*` variables, other tests leave naming, output_dir and compression_level alone
+    #[test]
+    fn layers_take_precedence_in_order() {
+        let dir = temp_dir("precedence");
+        fs::write(dir.join(PROJECT_CONFIG_FILE), "output_dir = \"project\"\ncompression_level = 5\n").unwrap();
+        let global = Config {
+            naming: "global".to_string(),
+            output_dir: Some("global".to_string()),
+            compression_level: Some(1),
+            ..Config::default()
+        };
+        std::env::set_var("MIA_NAMING", "env");
+        std::env::set_var("MIA_OUTPUT_DIR", "env");
+        std::env::set_var("MIA_COMPRESSION_LEVEL", "3");
+        let layered = global.layered(&dir);
+        for variable in ["MIA_NAMING", "MIA_OUTPUT_DIR", "MIA_COMPRESSION_LEVEL"] {
+            std::env::remove_var(variable);
+        }
+        let (mut config, sources) = layered.unwrap();
+
+        // Env vars beat the global config, project files beat env vars
+        assert_eq!(config.naming, "env");
+        assert!(matches!(&sources["naming"], Source::Env(variable) if variable == "MIA_NAMING"));
+        assert_eq!(config.output_dir.as_deref(), Some("project"));
+        let project = dir.canonicalize().unwrap().join(PROJECT_CONFIG_FILE);
+        assert!(matches!(&sources["output_dir"], Source::Project(file) if *file == project));
+        assert_eq!(config.compression_level, Some(5));
+        assert!(!sources.contains_key("format"));
+
+        // Command line flags are applied last, the same way `mia create` does
+        let level = Some(7);
+        if level.is_some() { config.compression_level = level; }
+        assert_eq!(config.compression_level, Some(7));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn project_files_cant_set_global_only_keys() {
+        for key in GLOBAL_ONLY_KEYS {
+            let dir = temp_dir(&format!("global-only-{key}"));
+            let value = if key == "keep_versions" { "2" } else { "\"prerelease\"" };
+            fs::write(dir.join(PROJECT_CONFIG_FILE), format!("{key} = {value}\n")).unwrap();
+            let Err(error) = Config::default().layered(&dir) else {
+                panic!("`{key}` was accepted in a project file");
+            };
+            assert!(format!("{error:?}").contains(&format!("`{key}` can only be set in the global config")), "{error:?}");
+            fs::remove_dir_all(dir).unwrap();
+        }
+    }
+
+    #[test]
+    fn profiles_override_layered_values() {
+        let dir = temp_dir("profiles");
+        fs::write(
+            dir.join(PROJECT_CONFIG_FILE),
+            "format = \"tar-gz\"\nblacklisted_file_extensions = [\"log\"]\n\n\
+            [profiles.release]\nformat = \"zip\"\nblacklisted_file_extensions = [\"tmp\"]\n",
+        ).unwrap();
+        let (mut config, mut sources) = Config::default().layered(&dir).unwrap();
+        assert_eq!(config.format, ArchiveFormat::TarGz);
+
+        config.apply_profile("release", &mut sources).unwrap();
+        assert_eq!(config.format, ArchiveFormat::Zip);
+        assert!(matches!(&sources["format"], Source::Profile(name) if name == "release"));
+        // Profile blacklists are added to the layered ones
+        assert_eq!(config.blacklisted_file_extensions, ["log", "tmp"]);
+        assert!(matches!(&sources["blacklisted_file_extensions"], Source::Profile(name) if name == "release"));
+
+        let Err(error) = config.apply_profile("missing", &mut sources) else {
+            panic!("an unknown profile was accepted");
+        };
+        assert!(format!("{error:?}").contains("Couldn't find profile `missing`"), "{error:?}");
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -2,37 +2,52 @@
 // Author: Derek Blaney
 
 use std::collections::HashMap;
+use crate::archive::{check_level, new_writer, source_date_epoch, ArchiveWriter, EncodedEntry, EntryEncoder, EntryMetadata, SymlinkPolicy};
 use crate::config::Config;
 use crate::error;
 use chrono::{DateTime, Utc};
 use error::Error;
 use inline_colorization::*;
-use std::ffi::{OsStr, OsString};
+use std::ffi::OsString;
 use std::fs;
 use std::fs::File;
-use std::io::{Write};
 use std::ops::{Add};
 use std::path::{Path, PathBuf};
 use std::string::String;
 use std::time::{Instant, SystemTime};
-use zip::write::FileOptions;
-use zip::ZipWriter;
-use crate::languages::{detect_language, Language};
+use crate::ignore_files::IgnoreStack;
+use crate::languages::{Language, Languages, LineStats};
+use crate::patterns::{Exclusion, Filters};
+use crate::manifest::{sha256_hex, Manifest, ManifestEntry, ManifestFormat};
+use crate::report::{EntryKind, Report, ReportEntry, ReportOptions};
+use crate::stats::FileStats;
+use crate::password::Password;
+use rayon::prelude::*;
 
 pub struct Directory {
     pub location: PathBuf,
     name: OsString,
-    zip: ZipWriter<File>,
+    // Opened by `zip_it`, so a dry run never creates the file
+    archive: Option<Box<dyn ArchiveWriter>>,
+    password: Option<Password>,
+    zip_path: PathBuf,
     config: Config,
     verbose: bool,
+    encrypted: bool,
     exclude: Vec<String>,
     include: Vec<String>,
+    filters: Filters,
+    ignores: IgnoreStack,
+    languages: Languages,
     count: i32,
     out: Option<String>,
-    lines: HashMap<Language, i128>
+    lines: LineTotals,
+    report: Option<(Report, ReportOptions)>,
+    manifest: Option<(Manifest, ManifestFormat)>
 }
 
 impl Directory {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         location: &OsString,
         name: &Option<OsString>,
@@ -40,7 +55,8 @@ impl Directory {
         verbose: bool,
         exclude: Vec<String>,
         include: Vec<String>,
-        out: Option<String>
+        out: Option<String>,
+        password: Option<Password>
     ) -> Result<Directory, Error> {
         let path = Path::new(&location);
         if !&path.exists() {
@@ -66,34 +82,59 @@ impl Directory {
         for (key, value) in map {
             zip_name = zip_name.replace(key, &value);
         }
-        zip_name = zip_name.add(".zip");
+        zip_name = zip_name.add(config.format.extension());
 
-        let save_path = if let Some(out) = out {
-            let p = Path::new(&out).to_path_buf();
-            fs::create_dir_all(&p)?;
-            if !p.exists() { path.to_owned() }  else { p }
-        } else {
-            path.to_owned()
+        let save_path = match out {
+            Some(out) => PathBuf::from(out),
+            None => path.to_owned(),
         };
 
-        let zip_path = &save_path.join(&zip_name);
-        let zip_file = File::create(zip_path).map_err(Error::IO)?;
-        let zip = ZipWriter::new(zip_file);
+        check_level(&config)?;
+        let encrypted = password.is_some();
+        let zip_path = save_path.join(&zip_name);
+        let ignores = IgnoreStack::new(config.use_ignore_files);
+        let filters = Filters::new(path, &config, &exclude, &include)?;
+        let languages = Languages::load(Some(path), config.detect_modelines)?;
 
         Ok(Directory {
             location: path.to_owned(),
             name: zip_name.into(),
-            zip,
+            archive: None,
+            password,
+            zip_path: zip_path.clone(),
             config,
             verbose,
+            encrypted,
             exclude,
             include,
+            filters,
+            ignores,
+            languages,
             count: 0,
             out: zip_path.clone().into_os_string().into_string().ok(),
-            lines: HashMap::new()
+            lines: LineTotals::default(),
+            report: None,
+            manifest: None
         })
     }
 
+    // Collects every walked path into a report, written once the archive is done.
+    // A report printed to the terminal replaces the usual output.
+    pub fn with_report(mut self, options: ReportOptions) -> Self {
+        if options.file.is_none() {
+            self.verbose = false;
+        }
+        self.report = Some((Report::new(), options));
+        self
+    }
+
+    // Hashes every file and writes a manifest entry after the last one
+    pub fn with_manifest(mut self, format: ManifestFormat) -> Self {
+        let created = if self.config.reproducible { source_date_epoch() } else { Utc::now().timestamp() };
+        self.manifest = Some((Manifest::new(created), format));
+        self
+    }
+
     // Zip the directory (initial action)
     pub fn zip_it(&mut self) -> Result<(), Error> {
         if self.verbose {
@@ -104,125 +145,427 @@ impl Directory {
                 .blacklisted_file_names, &self.config.blacklisted_folder_names, &self.exclude];
             println!("Excluding: {:?} (Use --exclude or -e)", excluding);
             println!("Including: {:?} (Use --include or -i)", self.include);
+            if self.encrypted {
+                println!("Encryption: {color_cyan}AES-256{color_reset}");
+            }
+            if self.config.reproducible {
+                println!("Reproducible: {color_cyan}timestamps pinned to {}{color_reset}", source_date_epoch());
+            }
             println!("--------------------------------------");
         }
         let start = Instant::now();
+        if let Some(parent) = self.zip_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let zip_file = File::create(&self.zip_path).map_err(Error::IO)?;
+        self.archive = Some(new_writer(&self.config, zip_file, self.password.take())?);
         self.add_to_zip(&self.location.to_owned())?;
-        self.zip.finish().map_err(Error::ZipFileFail)?;
+        self.write_manifest()?;
+        self.writer()?.finish()?;
         let elapsed = start.elapsed().as_millis();
-        let line_count = &self.lines
-            .iter()
-            .filter(|(language, _)| *language != &Language::None)
-            .map(|(_, &count)| count)
-            .sum::<i128>();
+        if let Some((mut report, options)) = self.report.take() {
+            report.archive = self.out.clone();
+            report.archive_size = self.out.as_ref().and_then(|out| fs::metadata(out).ok()).map(|m| m.len()).unwrap_or(0);
+            report.elapsed_ms = elapsed;
+            report.write(&options)?;
+            if options.file.is_none() {
+                return Ok(());
+            }
+        }
+        let line_count = self.lines.total().lines();
         println!(
             "Zipped {color_cyan}{}{color_reset} files in {color_cyan}{}ms{color_reset} ({color_cyan}{line_count}{color_reset} lines)",
             self.count, elapsed
         );
         if self.verbose {
-            println!("--------------------------------------");
-            for (&lang, &count) in &self.lines {
-                if lang == Language::None {
-                    println!("Other: {color_cyan}{count}{color_reset} lines");
-                    continue;
-                }
-                let percentage: f64 = ((count as f64/(*line_count) as f64)*10000.0).round()/100.0;
-                println!("{lang:?}: {color_cyan}{count}{color_reset} lines ({percentage}%)")
+            self.lines.print();
+        }
+        Ok(())
+    }
+
+    fn write_manifest(&mut self) -> Result<(), Error> {
+        let Some((manifest, format)) = self.manifest.take() else { return Ok(()) };
+        let metadata = EntryMetadata { mtime: Some(manifest.created_at), mode: Some(0o644) };
+        let writer = self.writer()?;
+        let entry = writer.encoder().encode(format.file_name(), manifest.render(format)?, metadata)?;
+        writer.write_entry(entry)?;
+        if self.verbose {
+            println!("[FILE] {color_green}+{color_reset} {color_cyan}{:?}{color_reset} ({} files)",
+                     format.file_name(), manifest.files.len());
+        }
+        Ok(())
+    }
+
+    fn writer(&mut self) -> Result<&mut Box<dyn ArchiveWriter>, Error> {
+        self.archive.as_mut().ok_or_else(|| "The archive isn't open".into())
+    }
+
+    fn add_to_report(&mut self, entry: impl FnOnce() -> ReportEntry) {
+        if let Some((report, _)) = &mut self.report {
+            report.push(entry());
+        }
+    }
+
+    // Walks and filters the same way as `zip_it`, without reading any file or creating the archive.
+    // Prints a tree of what would be included and excluded, or the report if one was asked for.
+    pub fn dry_run(&mut self) -> Result<(), Error> {
+        let start = Instant::now();
+        let walker = Walker::new(&self.location, &self.filters, &self.config).skipping(&self.zip_path);
+        let events = walker.walk(&self.location, &self.ignores)?;
+        let mut report = Report::new();
+        for event in &events {
+            report.push(event.report_entry());
+        }
+        report.elapsed_ms = start.elapsed().as_millis();
+
+        if let Some((_, options)) = self.report.take() {
+            report.write(&options)?;
+            if options.file.is_none() {
+                return Ok(());
             }
-            println!("--------------------------------------");
         }
+        println!("Would write: {color_cyan}{:?}{color_reset}", self.zip_path);
+        println!("--------------------------------------");
+        report.print_tree();
+        println!("--------------------------------------");
+        println!(
+            "Dry run: {color_cyan}{}{color_reset} files would be archived ({color_cyan}{}{color_reset} bytes \
+            uncompressed), {color_cyan}{}{color_reset} entries excluded. Nothing was written.",
+            report.totals.files, report.totals.size, report.totals.excluded
+        );
         Ok(())
     }
 
     // Add directory to zip for iteration
     fn add_to_zip(&mut self, location: &Path) -> Result<(), Error> {
-        let paths = fs::read_dir(location)?;
-        for path in paths.flatten() {
-            let location = &path.path();
-            let file_name = os_string_to_lower_string(location.file_name());
-            let file_extension = os_string_to_lower_string(location.extension());
-
-            if self.exclude.contains(&file_name) && !self.include.contains(&file_name) { continue; }
-
-            if location.is_dir() {
-                if self.config.blacklisted_folder_names.contains(&file_name) && !self.include.contains(&file_name) {
-                    if self.verbose {
-                        println!("[DIR] {color_yellow}/{color_reset} {color_cyan}{:?}{color_reset}",
-                                 location);
+        let walker = Walker::new(&self.location, &self.filters, &self.config).skipping(&self.zip_path);
+        let events = walker.walk(location, &self.ignores)?;
+        let encoder = self.writer()?.encoder();
+        let hash = self.manifest.is_some();
+
+        // Files of a batch are read, counted and compressed in parallel, then written in walk order
+        for batch in batches(&events) {
+            let prepared = batch
+                .par_iter()
+                .map(|event| match event {
+                    WalkEvent::File(mia_file) => mia_file.prepare(&encoder, &self.languages, hash).map(Some),
+                    _ => Ok(None),
+                })
+                .collect::<Result<Vec<_>, Error>>()?;
+
+            for (event, prepared) in batch.iter().zip(prepared) {
+                match (event, prepared) {
+                    (WalkEvent::File(mia_file), Some(prepared)) => {
+                        self.add_prepared(mia_file, prepared)?;
                     }
-                    continue;
-                }
-                self.add_to_zip(location)?;
-                if self.verbose {
-                    println!("[DIR] {color_green}+{color_reset} {color_cyan}{:?}{color_reset}",
-                             location);
+                    (WalkEvent::Symlink(link), _) => {
+                        self.writer()?.write_symlink(&link.stripped_path, &link.target, link.metadata)?;
+                        if self.verbose {
+                            println!(
+                                "[LINK] {color_green}+{color_reset} {color_cyan}{:?}{color_reset} -> {:?}",
+                                &link.stripped_path, &link.target
+                            );
+                        }
+                        self.add_to_report(|| event.report_entry());
+                        self.count += 1;
+                    }
+                    (WalkEvent::Dir(dir), _) => {
+                        if self.verbose {
+                            println!("[DIR] {color_green}+{color_reset} {color_cyan}{:?}{color_reset}",
+                                     dir.location);
+                        }
+                        self.add_to_report(|| event.report_entry());
+                    }
+                    (WalkEvent::Excluded(excluded), _) => {
+                        if self.verbose && excluded.is_dir {
+                            println!("[DIR] {color_yellow}/{color_reset} {color_cyan}{:?}{color_reset}",
+                                     excluded.location);
+                        }
+                        self.add_to_report(|| event.report_entry());
+                    }
+                    (WalkEvent::File(_), None) => {}
                 }
-            } else if location.is_file() {
-                let mia_file: MiaFile = MiaFile::new (
-                    file_name,
-                    file_extension,
-                    location
-                );
-
-                if self.config.blacklisted_file_names.contains(&mia_file.name) && !self.include.contains(&mia_file.name) { continue; }
-                if self.config.blacklisted_file_extensions.contains(&mia_file.extension) && !self.include.contains(&mia_file.extension) { continue; }
-
-                let stripped_path = location
-                    .strip_prefix(&self.location)
+            }
+        }
+        Ok(())
+    }
+
+    fn add_prepared(&mut self, mia_file: &MiaFile, prepared: PreparedFile) -> Result<(), Error> {
+        if let Some((manifest, format)) = &mut self.manifest {
+            if mia_file.stripped_path == format.file_name() {
+                return Err(Error::Custom(format!(
+                    "The folder already has a {} file, it would clash with the manifest",
+                    format.file_name()
+                )));
+            }
+            manifest.files.push(ManifestEntry {
+                path: mia_file.stripped_path.clone(),
+                sha256: prepared.sha256.clone().unwrap_or_default(),
+                size: prepared.size,
+                mtime: mia_file.metadata.mtime,
+                language: prepared.lines.language.to_string(),
+            });
+        }
+        self.writer()?.write_entry(prepared.entry)?;
+        self.lines.add(&prepared.lines);
+        self.add_to_report(|| {
+            ReportEntry::included(&mia_file.stripped_path, EntryKind::File, prepared.size)
+                .with_lines(&prepared.lines.language, prepared.lines.lines, prepared.lines.generated)
+        });
+
+        if self.verbose {
+            let lines_text = prepared.lines.describe();
+            println!(
+                "[FILE] {color_green}+{color_reset} {color_cyan}{:?}{color_reset} \
+                {color_yellow}{lines_text}{color_reset}",
+                &mia_file.stripped_path
+            );
+        }
+
+        self.count += 1;
+        Ok(())
+    }
+}
+
+// Files handed to the worker pool at once, and their total size. Bounds how much content is held in memory.
+const BATCH_SIZE: usize = 256;
+const BATCH_BYTES: u64 = 64 * 1024 * 1024;
+
+// Splits the walk in batches of at most `BATCH_SIZE` files and `BATCH_BYTES`. A file bigger than that
+// gets a batch of its own, so only one of them is in memory at a time.
+fn batches(events: &[WalkEvent]) -> Vec<&[WalkEvent]> {
+    let mut batches = Vec::new();
+    let (mut start, mut files, mut bytes) = (0, 0, 0);
+    for (index, event) in events.iter().enumerate() {
+        let WalkEvent::File(file) = event else { continue };
+        if files > 0 && (files == BATCH_SIZE || bytes + file.size > BATCH_BYTES) {
+            batches.push(&events[start..index]);
+            (start, files, bytes) = (index, 0, 0);
+        }
+        files += 1;
+        bytes += file.size;
+    }
+    if start < events.len() {
+        batches.push(&events[start..]);
+    }
+    batches
+}
+
+pub enum WalkEvent {
+    File(MiaFile),
+    // Only with `SymlinkPolicy::Store`
+    Symlink(MiaSymlink),
+    // Emitted after the directory's content
+    Dir(MiaDir),
+    // Left out by a pattern, an ignore file or the symlink policy. Excluded folders aren't walked.
+    Excluded(MiaExcluded),
+}
+
+impl WalkEvent {
+    pub fn report_entry(&self) -> ReportEntry {
+        match self {
+            WalkEvent::File(file) => ReportEntry::included(&file.stripped_path, EntryKind::File, file.size),
+            WalkEvent::Symlink(link) => ReportEntry::included(&link.stripped_path, EntryKind::Symlink, 0),
+            WalkEvent::Dir(dir) => ReportEntry::included(&dir.stripped_path, EntryKind::Dir, 0),
+            WalkEvent::Excluded(excluded) => {
+                let kind = if excluded.is_dir { EntryKind::Dir } else { EntryKind::File };
+                ReportEntry::excluded(&excluded.stripped_path, kind, excluded.size, excluded.reason)
+            }
+        }
+    }
+}
+
+// The filtering part of the walk, split from `Directory` so it can be shared between threads
+// and reused by `mia stats`
+pub struct Walker<'a> {
+    root: &'a Path,
+    filters: &'a Filters,
+    symlinks: SymlinkPolicy,
+    // Set for reproducible archives
+    pinned_time: Option<i64>,
+    // The archive being written, canonical. It can sit inside the walked folder.
+    output: Option<PathBuf>,
+}
+
+impl<'a> Walker<'a> {
+    pub fn new(root: &'a Path, filters: &'a Filters, config: &Config) -> Self {
+        Walker {
+            root,
+            filters,
+            symlinks: config.symlinks,
+            pinned_time: config.reproducible.then(source_date_epoch),
+            output: None,
+        }
+    }
+
+    // Skips the archive itself whatever the format and the blacklists. It may not exist yet.
+    pub fn skipping(mut self, output: &Path) -> Self {
+        let parent = output.parent().and_then(|parent| parent.canonicalize().ok());
+        self.output = match (parent, output.file_name()) {
+            (Some(parent), Some(name)) => Some(parent.join(name)),
+            _ => None,
+        };
+        self
+    }
+
+    fn is_output(&self, location: &Path) -> bool {
+        let Some(output) = &self.output else { return false };
+        location.file_name() == output.file_name() && location.canonicalize().is_ok_and(|location| &location == output)
+    }
+
+    // Sibling entries are walked in parallel, the result is sorted by name so it doesn't depend on timing
+    pub fn walk(&self, location: &Path, ignores: &IgnoreStack) -> Result<Vec<WalkEvent>, Error> {
+        self.walk_folder(location, ignores, None)
+    }
+
+    // `pruned` is set inside an excluded folder that's only walked for `--include` patterns,
+    // everything they don't match is left out for the folder's reason
+    fn walk_folder(
+        &self,
+        location: &Path,
+        ignores: &IgnoreStack,
+        pruned: Option<Exclusion>,
+    ) -> Result<Vec<WalkEvent>, Error> {
+        let ignores = ignores.child(location);
+        let mut paths: Vec<PathBuf> = fs::read_dir(location)?
+            .flatten()
+            .map(|entry| entry.path())
+            .collect();
+        paths.sort();
+
+        let events = paths
+            .par_iter()
+            .map(|location| -> Result<Vec<WalkEvent>, Error> {
+                let stripped_path = || location
+                    .strip_prefix(self.root)
                     .unwrap()
                     .to_path_buf()
                     .into_os_string()
                     .into_string()
                     .unwrap();
+                let excluded = |is_dir: bool, size: u64, reason: Exclusion| {
+                    Ok(vec![WalkEvent::Excluded(MiaExcluded {
+                        location: location.clone(),
+                        stripped_path: stripped_path(),
+                        is_dir,
+                        size,
+                        reason,
+                    })])
+                };
 
-                let content = mia_file.get_content()?;
-
-                self.zip.start_file(&stripped_path, FileOptions::default())?;
-                self.zip.write_all(&content)?;
-
-                let lines = mia_file.count_lines();
-                let entry = &mut self.lines.entry(lines.0);
-
-                match entry {
-                    std::collections::hash_map::Entry::Occupied(ref mut occupied) => {
-                        // Increment the value if the key exists
-                        *occupied.get_mut() += lines.1;
+                if self.is_output(location) {
+                    return Ok(Vec::new());
+                }
+                let link_metadata = fs::symlink_metadata(location)?;
+                let is_link = link_metadata.file_type().is_symlink();
+                if is_link && self.symlinks == SymlinkPolicy::Skip {
+                    return excluded(false, 0, Exclusion::SkippedSymlink);
+                }
+                let store_link = is_link && self.symlinks == SymlinkPolicy::Store;
+                let metadata = if is_link && !store_link {
+                    match fs::metadata(location) {
+                        Ok(metadata) => metadata,
+                        // Broken link, nothing to follow
+                        Err(_) => return excluded(false, 0, Exclusion::BrokenSymlink),
                     }
-                    std::collections::hash_map::Entry::Vacant(_vacant) => {
-                        // Add the key with value 1 if the key doesn't exist
-                        self.lines.insert(lines.0, lines.1);
+                } else {
+                    link_metadata
+                };
+                let is_dir = metadata.is_dir();
+
+                // `--include` wins over the exclusions, see `Filters`
+                if !self.filters.is_included(location, is_dir) {
+                    let reason = pruned.or_else(|| self.filters.exclusion(location, is_dir)).or_else(|| {
+                        ignores.is_ignored(location, is_dir).then_some(Exclusion::IgnoreFile)
+                    });
+                    if let Some(reason) = reason {
+                        if is_dir && !store_link && self.filters.may_include_below(location) {
+                            let mut events = self.walk_folder(location, &ignores, Some(reason))?;
+                            if events.iter().any(|event| !matches!(event, WalkEvent::Excluded(_))) {
+                                events.push(WalkEvent::Dir(MiaDir {
+                                    location: location.clone(),
+                                    stripped_path: stripped_path(),
+                                }));
+                                return Ok(events);
+                            }
+                        }
+                        let size = if is_dir { 0 } else { metadata.len() };
+                        return excluded(is_dir, size, reason);
                     }
                 }
-
-                if self.verbose {
-                    let lines_text = if lines.1 > 0 { format!("({} lines)", lines.1) } else { String::new() };
-                    println!(
-                        "[FILE] {color_green}+{color_reset} {color_cyan}{:?}{color_reset} \
-                        {color_yellow}{lines_text}{color_reset}",
-                        &stripped_path
-                    );
+                let mut entry_metadata = EntryMetadata::from(&metadata);
+                if let Some(epoch) = self.pinned_time {
+                    entry_metadata = entry_metadata.pinned(epoch);
                 }
 
-                self.count += 1;
-            }
-        }
-        Ok(())
+                if store_link {
+                    let target = fs::read_link(location)?.to_string_lossy().to_string();
+                    Ok(vec![WalkEvent::Symlink(MiaSymlink {
+                        stripped_path: stripped_path(),
+                        target,
+                        metadata: entry_metadata,
+                    })])
+                } else if is_dir {
+                    let mut events = self.walk_folder(location, &ignores, pruned)?;
+                    events.push(WalkEvent::Dir(MiaDir {
+                        location: location.clone(),
+                        stripped_path: stripped_path(),
+                    }));
+                    Ok(events)
+                } else if metadata.is_file() {
+                    Ok(vec![WalkEvent::File(MiaFile::new(location, stripped_path(), metadata.len(), entry_metadata))])
+                } else {
+                    Ok(Vec::new())
+                }
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+        Ok(events.into_iter().flatten().collect())
     }
 }
 
-struct MiaFile {
-    name: String,
-    extension: String,
-    location: PathBuf
+struct PreparedFile {
+    entry: EncodedEntry,
+    size: u64,
+    lines: LineCount,
+    // Only computed for manifests
+    sha256: Option<String>,
+}
+
+pub struct MiaDir {
+    pub location: PathBuf,
+    pub stripped_path: String,
+}
+
+pub struct MiaExcluded {
+    pub location: PathBuf,
+    pub stripped_path: String,
+    pub is_dir: bool,
+    // 0 for folders, their content isn't walked
+    pub size: u64,
+    pub reason: Exclusion,
+}
+
+pub struct MiaSymlink {
+    stripped_path: String,
+    target: String,
+    metadata: EntryMetadata
+}
+
+pub struct MiaFile {
+    location: PathBuf,
+    stripped_path: String,
+    // From the walk, the content may have changed since
+    size: u64,
+    metadata: EntryMetadata
 }
 
 impl MiaFile {
-    fn new(name: String, extension: String, location: &PathBuf) -> Self {
-        let name = name.replace(&".".to_string().add(&extension), "");
+    fn new(location: &Path, stripped_path: String, size: u64, metadata: EntryMetadata) -> Self {
         MiaFile {
-            name,
-            extension,
-            location: location.to_owned()
+            location: location.to_owned(),
+            stripped_path,
+            size,
+            metadata
         }
     }
 
@@ -230,23 +573,165 @@ impl MiaFile {
         fs::read(&self.location).map_err(|_| Error::CantReadFile)
     }
 
-    fn count_lines(&self) -> (Language, i128) {
-        let language = detect_language(&self.extension);
-        let content = self.get_content().unwrap();
-        let text_content = std::str::from_utf8(&content);
-        if let Ok(text) = text_content {
-            (language, text.lines().filter(|line| !line.trim().is_empty()).count() as i128)
-        } else { (language, 0) }
+    // Reads the file once, the same content feeds the line counter and the archive entry
+    fn prepare(&self, encoder: &EntryEncoder, languages: &Languages, hash: bool) -> Result<PreparedFile, Error> {
+        let content = self.get_content()?;
+        let size = content.len() as u64;
+        let lines = count_lines(languages, Path::new(&self.stripped_path), &content);
+        let sha256 = hash.then(|| sha256_hex(&content));
+        let entry = encoder.encode(&self.stripped_path, content, self.metadata)?;
+        Ok(PreparedFile { entry, size, lines, sha256 })
     }
+
+    // Only reads and counts, for `mia stats`
+    pub fn stats(&self, languages: &Languages) -> Result<FileStats, Error> {
+        let content = self.get_content()?;
+        let count = count_lines(languages, Path::new(&self.stripped_path), &content);
+        Ok(FileStats {
+            path: PathBuf::from(&self.stripped_path),
+            size: content.len() as u64,
+            language: count.language,
+            lines: count.lines,
+            generated: count.generated,
+        })
+    }
+}
+
+// What counting a single file found
+pub struct LineCount {
+    pub language: Language,
+    pub lines: LineStats,
+    pub generated: bool,
 }
 
-fn os_string_to_lower_string(os_string: Option<&OsStr>) -> String {
-    match os_string {
-        Some(text) => text
-            .to_os_string()
-            .into_string()
-            .unwrap()
-            .to_ascii_lowercase(),
-        None => "".to_string(),
+impl LineCount {
+    // `(12 lines)` for the verbose file listings
+    pub fn describe(&self) -> String {
+        let mut text = if self.lines.lines() > 0 { format!("({} lines)", self.lines.lines()) } else { String::new() };
+        if self.generated {
+            text.push_str(" [generated]");
+        }
+        text
     }
-}
\ No newline at end of file
+}
+
+// Code, comment and blank lines of a file's content. Binary (non UTF-8) content counts as 0 lines.
+// `path` is relative to the archive root.
+pub fn count_lines(languages: &Languages, path: &Path, content: &[u8]) -> LineCount {
+    let text = std::str::from_utf8(content).ok();
+    let detection = languages.detect(path, text.unwrap_or_default());
+    let lines = text.map(|text| detection.syntax.count(text)).unwrap_or_default();
+    LineCount {
+        language: detection.language,
+        lines,
+        generated: detection.generated,
+    }
+}
+
+// Line counts of a run per language. Generated and vendored files are kept apart.
+#[derive(Default)]
+pub struct LineTotals {
+    languages: HashMap<Language, LineStats>,
+    generated: LineStats,
+    generated_files: u64,
+}
+
+impl LineTotals {
+    pub fn add(&mut self, count: &LineCount) {
+        if count.generated {
+            self.generated += count.lines;
+            self.generated_files += 1;
+        } else {
+            *self.languages.entry(count.language.clone()).or_default() += count.lines;
+        }
+    }
+
+    // Lines of all known languages, `Language::other()` and generated files are left out
+    pub fn total(&self) -> LineStats {
+        let mut total = LineStats::default();
+        for (_, &stats) in self.languages.iter().filter(|(language, _)| !language.is_other()) {
+            total += stats;
+        }
+        total
+    }
+
+    pub fn print(&self) {
+        let total = self.total();
+        println!("--------------------------------------");
+        for (lang, stats) in &self.languages {
+            let breakdown = breakdown(stats);
+            if lang.is_other() {
+                println!("Other: {color_cyan}{}{color_reset} lines ({breakdown})", stats.lines());
+                continue;
+            }
+            let percentage: f64 = ((stats.lines() as f64/total.lines() as f64)*10000.0).round()/100.0;
+            println!("{lang}: {color_cyan}{}{color_reset} lines ({percentage}%) ({breakdown})", stats.lines())
+        }
+        if self.generated_files > 0 {
+            println!(
+                "Generated/vendored: {color_cyan}{}{color_reset} lines in {color_cyan}{}{color_reset} files ({})",
+                self.generated.lines(), self.generated_files, breakdown(&self.generated)
+            );
+        }
+        println!(
+            "Total: {color_cyan}{}{color_reset} lines ({})",
+            total.lines(), breakdown(&total)
+        );
+        println!("--------------------------------------");
+    }
+}
+
+fn breakdown(stats: &LineStats) -> String {
+    format!(
+        "{color_cyan}{}{color_reset} code, {color_cyan}{}{color_reset} comments, \
+        {color_cyan}{}{color_reset} blanks",
+        stats.code, stats.comments, stats.blanks
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::archive::ArchiveFormat;
+    use clap::ValueEnum;
+    use std::time::Duration;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("mia-dir-{}-{name}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn touch_tree(root: &Path, mtime: SystemTime) {
+        for path in ["main.rs", "src/lib.rs"] {
+            File::options().write(true).open(root.join(path)).unwrap().set_modified(mtime).unwrap();
+        }
+    }
+
+    #[test]
+    fn reproducible_archives_are_byte_identical() {
+        let source = temp_dir("reproducible-source");
+        fs::create_dir_all(source.join("src")).unwrap();
+        fs::write(source.join("main.rs"), "fn main() {}\n").unwrap();
+        fs::write(source.join("src/lib.rs"), "// lib\npub fn lib() {}\n").unwrap();
+        let out = temp_dir("reproducible-out");
+
+        for format in ArchiveFormat::value_variants() {
+            let mut archives = vec![];
+            for (run, mtime) in [SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000_000), SystemTime::now()].into_iter().enumerate() {
+                touch_tree(&source, mtime);
+                let config = Config { format: *format, reproducible: true, ..Config::default() };
+                                let mut directory = Directory::new(
+                    &source.clone().into_os_string(), &Some("tree".into()), config, false, vec![], vec![],
+                    Some(out.join(format!("{run}")).to_string_lossy().into_owned()), None,
+                ).unwrap().with_manifest(ManifestFormat::Json);
+                directory.zip_it().unwrap();
+                archives.push(fs::read(&directory.zip_path).unwrap());
+            }
+            assert_eq!(archives[0], archives[1], "{format:?}");
+        }
+        fs::remove_dir_all(source).unwrap();
+        fs::remove_dir_all(out).unwrap();
+    }
+}
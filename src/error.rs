@@ -5,7 +5,7 @@ use confy::ConfyError;
 use std::fmt::{Debug, Formatter};
 use zip::result::ZipError;
 
-#[allow(clippy::upper_case_acronyms)]
+#[allow(clippy::upper_case_acronyms, clippy::enum_variant_names)]
 pub enum Error {
     PathNotFound,
     PathNotDir,
@@ -16,6 +16,8 @@ pub enum Error {
     ConfigActionError(String),
     Request(reqwest::Error),
     JSON(serde_json::Error),
+    UnsafeEntryPath(String),
+    InvalidPattern(String),
     Custom(String)
 }
 
@@ -40,6 +42,10 @@ impl Debug for Error {
             Error::Request(error) => format!("Request error: {:?}", error),
             // JSON
             Error::JSON(error) => format!("JSON error: error {error}"),
+            // Archive entry would be written outside of the destination (zip-slip)
+            Error::UnsafeEntryPath(name) => format!("Refusing to extract unsafe entry path: {name:?}"),
+            // Include/exclude pattern that isn't a valid glob
+            Error::InvalidPattern(error) => format!("Invalid pattern: {error}"),
             // Custom
             Error::Custom(error) => format!("Error: {error}")
         };
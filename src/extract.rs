@@ -0,0 +1,551 @@
+// Archive Extractor
+
+use crate::error::Error;
+use crate::password::{Password, PasswordArgs};
+use crate::patterns::Filters;
+use clap::ValueEnum;
+use inline_colorization::*;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{Read, Write};
+use std::path::{Component, Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+use zip::result::ZipError;
+use zip::{ExtraField, ZipArchive};
+
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq, ValueEnum)]
+pub enum OverwriteMode {
+    /// Keep the existing file
+    #[default]
+    Skip,
+    /// Replace the existing file
+    Overwrite,
+    /// Ask for every existing file
+    Ask,
+    /// Extract next to the existing file as `name (1).ext`
+    Rename,
+}
+
+pub struct Extractor {
+    archive: PathBuf,
+    dest: PathBuf,
+    overwrite: OverwriteMode,
+    verbose: bool,
+    exclude: Vec<String>,
+    include: Vec<String>,
+    filters: Filters,
+    count: i32,
+    skipped: i32,
+}
+
+impl Extractor {
+    pub fn new(
+        archive: PathBuf,
+        dest: Option<PathBuf>,
+        overwrite: OverwriteMode,
+        verbose: bool,
+        exclude: Vec<String>,
+        include: Vec<String>,
+    ) -> Result<Extractor, Error> {
+        if !archive.is_file() {
+            return Err(Error::PathNotFound);
+        }
+        // Defaults to a folder named after the archive, next to it
+        let dest = match dest {
+            Some(dest) => dest,
+            None => {
+                let stem = archive.file_stem().unwrap_or_default().to_owned();
+                archive.with_file_name(stem)
+            }
+        };
+
+        // Entry paths are relative, so patterns are anchored to the archive root
+        let filters = Filters::from_args(Path::new(""), &exclude, &include)?;
+
+        Ok(Extractor {
+            archive,
+            dest,
+            overwrite,
+            verbose,
+            exclude,
+            include,
+            filters,
+            count: 0,
+            skipped: 0,
+        })
+    }
+
+    pub fn extract(&mut self, password_args: &PasswordArgs) -> Result<(), Error> {
+        let start = Instant::now();
+        let mut zip = ZipArchive::new(File::open(&self.archive)?)?;
+
+        // Reject the whole archive before anything touches the disk
+        let mut paths = Vec::with_capacity(zip.len());
+        for index in 0..zip.len() {
+            let entry = zip.by_index_raw(index)?;
+            let path = safe_entry_path(entry.name())
+                .ok_or_else(|| Error::UnsafeEntryPath(entry.name().to_string()))?;
+            paths.push(path);
+        }
+
+        let password = password_args.read_for(&mut zip)?;
+
+        if self.verbose {
+            println!("--------------------------------------");
+            println!("Extracting: {color_cyan}{:?}{color_reset}", self.archive);
+            println!("Output: {color_cyan}{:?}{color_reset}", self.dest);
+            println!("Excluding: {:?} (Use --exclude or -e)", self.exclude);
+            println!("Including: {:?} (Use --include or -i)", self.include);
+            println!("--------------------------------------");
+        }
+
+        fs::create_dir_all(&self.dest)?;
+        // Links come last, so no file is ever written through one
+        let mut links = Vec::new();
+        for (index, path) in paths.iter().enumerate() {
+            let entry = zip.by_index_raw(index)?;
+            let (is_dir, is_symlink) = (entry.is_dir(), entry.is_symlink());
+            drop(entry);
+            if self.is_filtered(path, is_dir) {
+                continue;
+            }
+            if is_symlink {
+                links.push((index, path));
+                continue;
+            }
+            self.extract_entry(&mut zip, index, path, &password)?;
+        }
+        let mut created = Vec::new();
+        for (index, path) in links {
+            if self.extract_entry(&mut zip, index, path, &password)? {
+                created.push(path);
+            }
+        }
+        self.check_links(&created)?;
+
+        let elapsed = start.elapsed().as_millis();
+        println!(
+            "Extracted {color_cyan}{}{color_reset} files in {color_cyan}{}ms{color_reset} \
+            ({color_cyan}{}{color_reset} skipped)",
+            self.count, elapsed, self.skipped
+        );
+        Ok(())
+    }
+
+    // Returns whether a symlink was created
+    fn extract_entry(
+        &mut self,
+        zip: &mut ZipArchive<File>,
+        index: usize,
+        path: &Path,
+        password: &Option<Password>,
+    ) -> Result<bool, Error> {
+        let mut entry = match password {
+            Some(password) => zip.by_index_decrypt(index, password.as_str().as_bytes()),
+            None => zip.by_index(index),
+        }
+        .map_err(|error| match error {
+            ZipError::InvalidPassword => "Incorrect password".into(),
+            error => Error::ZipFileFail(error),
+        })?;
+
+        let target = self.dest.join(path);
+        if entry.is_dir() {
+            fs::create_dir_all(&target)?;
+            return Ok(false);
+        }
+
+        let target = match self.resolve_existing(target)? {
+            Some(target) => target,
+            None => {
+                self.skipped += 1;
+                if self.verbose {
+                    println!("[FILE] {color_yellow}/{color_reset} {color_cyan}{:?}{color_reset}", path);
+                }
+                return Ok(false);
+            }
+        };
+
+        // A link extracted earlier could send this entry anywhere
+        if self.has_symlink_parent(path) {
+            return Err(Error::UnsafeEntryPath(entry.name().to_string()));
+        }
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        // Overwriting a link must replace it, not write through it
+        if is_symlink(&target) {
+            fs::remove_file(&target)?;
+        }
+
+        if entry.is_symlink() {
+            let mut link = String::new();
+            entry.read_to_string(&mut link)?;
+            if !is_safe_link(path, &link) {
+                return Err(Error::UnsafeEntryPath(format!("{} -> {link}", entry.name())));
+            }
+            if target.symlink_metadata().is_ok() {
+                fs::remove_file(&target)?;
+            }
+            create_symlink(&link, &target)?;
+            if self.verbose {
+                println!("[LINK] {color_green}+{color_reset} {color_cyan}{:?}{color_reset} -> {link:?}", path);
+            }
+            self.count += 1;
+            return Ok(true);
+        }
+
+        let mut file = File::create(&target)?;
+        io::copy(&mut entry, &mut file)?;
+
+        // Permissions and modification time, so executables stay executable. Setuid, setgid and sticky bits
+        // from an archive aren't trusted.
+        #[cfg(unix)]
+        if let Some(mode) = entry.unix_mode() {
+            use std::os::unix::fs::PermissionsExt;
+            file.set_permissions(fs::Permissions::from_mode(mode & 0o777))?;
+        }
+        if let Some(modified) = modified_time(&entry) {
+            file.set_modified(modified)?;
+        }
+
+        if self.verbose {
+            println!("[FILE] {color_green}+{color_reset} {color_cyan}{:?}{color_reset}", path);
+        }
+        self.count += 1;
+        Ok(false)
+    }
+
+    // Decides where an entry goes when its target already exists. `None` means skip it.
+    fn resolve_existing(&mut self, target: PathBuf) -> Result<Option<PathBuf>, Error> {
+        if !target.exists() {
+            return Ok(Some(target));
+        }
+        match self.overwrite {
+            OverwriteMode::Skip => Ok(None),
+            OverwriteMode::Overwrite => Ok(Some(target)),
+            OverwriteMode::Rename => Ok(Some(free_path(&target))),
+            OverwriteMode::Ask => {
+                print!("{color_cyan}{:?}{color_reset} already exists. Overwrite? [y]es/[n]o/[r]ename/[A]ll/[N]one: ", target);
+                io::stdout().flush()?;
+                let mut answer = String::new();
+                io::stdin().read_line(&mut answer)?;
+                match answer.trim() {
+                    "y" | "yes" => Ok(Some(target)),
+                    "r" | "rename" => Ok(Some(free_path(&target))),
+                    "A" | "all" => {
+                        self.overwrite = OverwriteMode::Overwrite;
+                        Ok(Some(target))
+                    }
+                    "N" | "none" => {
+                        self.overwrite = OverwriteMode::Skip;
+                        Ok(None)
+                    }
+                    _ => Ok(None),
+                }
+            }
+        }
+    }
+
+    // Every folder between the destination and the entry has to be a real folder
+    fn has_symlink_parent(&self, path: &Path) -> bool {
+        let mut current = self.dest.clone();
+        let mut parents = path.parent().map(Path::components).into_iter().flatten();
+        parents.any(|component| {
+            current.push(component);
+            is_symlink(&current)
+        })
+    }
+
+    // Links are checked once all of them exist: `l1 -> l2/..` looks harmless as text, and only leaves the
+    // destination once `l2 -> .` is created, whatever their order in the archive. Escaping links are removed.
+    fn check_links(&self, links: &[&PathBuf]) -> Result<(), Error> {
+        let mut escaping = Vec::new();
+        for path in links {
+            if !resolves_inside(&self.dest, path) {
+                fs::remove_file(self.dest.join(path))?;
+                escaping.push(path.display().to_string());
+            }
+        }
+        if escaping.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::UnsafeEntryPath(escaping.join(", ")))
+        }
+    }
+
+    // Same rules as the walk of `mia create`: every parent folder is checked first, like a pruned walk would
+    fn is_filtered(&self, path: &Path, is_dir: bool) -> bool {
+        if self.filters.is_included(path, is_dir) {
+            return false;
+        }
+        let components: Vec<Component> = path.components().collect();
+        let mut current = PathBuf::new();
+        for (index, component) in components.iter().enumerate() {
+            current.push(component);
+            let current_is_dir = is_dir || index + 1 < components.len();
+            if !self.filters.is_included(&current, current_is_dir)
+                && self.filters.is_excluded(&current, current_is_dir)
+            {
+                return true;
+            }
+        }
+        false
+    }
+}
+
+// Turns an entry name into a relative path, or `None` if it could escape the destination
+fn safe_entry_path(name: &str) -> Option<PathBuf> {
+    if name.contains('\0') || name.starts_with('/') || name.starts_with('\\') {
+        return None;
+    }
+    let mut path = PathBuf::new();
+    for part in name.split(['/', '\\']) {
+        match part {
+            "" | "." => continue,
+            ".." => return None,
+            // Drive letters and other prefixes (`C:`) on any platform
+            part if part.contains(':') => return None,
+            part => path.push(part),
+        }
+    }
+    if path.as_os_str().is_empty() || !path.components().all(|c| matches!(c, Component::Normal(_))) {
+        return None;
+    }
+    Some(path)
+}
+
+// Link targets have to be relative and stay inside the destination, like entry paths
+fn is_safe_link(path: &Path, link: &str) -> bool {
+    if link.starts_with('/') || link.starts_with('\\') || link.contains(':') || link.contains('\0') {
+        return false;
+    }
+    // Depth of the folder holding the link, relative to the destination
+    let mut depth = path.components().count() as i64 - 1;
+    for part in link.split(['/', '\\']) {
+        match part {
+            "" | "." => {}
+            ".." => {
+                depth -= 1;
+                if depth < 0 {
+                    return false;
+                }
+            }
+            _ => depth += 1,
+        }
+    }
+    true
+}
+
+// Most links followed while resolving a path, like the kernel's limit
+const MAX_LINKS: usize = 40;
+
+// Follows `path` (relative to `dest`) and every link on the way through the files on disk, without leaving
+// `dest`. `false` if it would, including through absolute links or a link loop. Missing parts are fine.
+fn resolves_inside(dest: &Path, path: &Path) -> bool {
+    let mut resolved: Vec<std::ffi::OsString> = Vec::new();
+    let mut pending: Vec<std::ffi::OsString> = path.iter().rev().map(|part| part.to_owned()).collect();
+    let mut followed = 0;
+    while let Some(part) = pending.pop() {
+        match part.to_str() {
+            Some("" | ".") => continue,
+            Some("..") => {
+                if resolved.pop().is_none() {
+                    return false;
+                }
+                continue;
+            }
+            _ => {}
+        }
+        resolved.push(part);
+        let current: PathBuf = dest.join(resolved.iter().collect::<PathBuf>());
+        if !is_symlink(&current) {
+            continue;
+        }
+        followed += 1;
+        let Ok(link) = fs::read_link(&current) else { return false };
+        if followed > MAX_LINKS || link.has_root() || link.components().any(|c| matches!(c, Component::Prefix(_))) {
+            return false;
+        }
+        // The link's target is relative to the folder holding it
+        resolved.pop();
+        let link = link.to_string_lossy().to_string();
+        pending.extend(link.split(['/', '\\']).rev().map(std::ffi::OsString::from));
+    }
+    true
+}
+
+fn is_symlink(path: &Path) -> bool {
+    path.symlink_metadata().is_ok_and(|metadata| metadata.file_type().is_symlink())
+}
+
+#[cfg(unix)]
+fn create_symlink(link: &str, target: &Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(link, target)
+}
+
+// Without Unix symlinks the link is written as a file holding its target, like `unzip` does
+#[cfg(not(unix))]
+fn create_symlink(link: &str, target: &Path) -> io::Result<()> {
+    fs::write(target, link)
+}
+
+// The extended timestamp field (0x5455) if another tool wrote one, it's exact and in UTC.
+// Otherwise the zip time, which is local time.
+fn modified_time(entry: &zip::read::ZipFile<File>) -> Option<SystemTime> {
+    let extended = entry.extra_data_fields().find_map(|field| match field {
+        ExtraField::ExtendedTimestamp(timestamp) => timestamp.mod_time(),
+        _ => None,
+    });
+    if let Some(seconds) = extended {
+        return Some(SystemTime::UNIX_EPOCH + Duration::from_secs(seconds.into()));
+    }
+    let time = entry.last_modified()?;
+    let date = chrono::NaiveDate::from_ymd_opt(time.year() as i32, time.month() as u32, time.day() as u32)?;
+    let date_time = date.and_hms_opt(time.hour() as u32, time.minute() as u32, time.second() as u32)?;
+    Some(date_time.and_local_timezone(chrono::Local).earliest()?.into())
+}
+
+fn free_path(target: &Path) -> PathBuf {
+    let stem = target.file_stem().unwrap_or_default().to_string_lossy().to_string();
+    let extension = target
+        .extension()
+        .map(|extension| format!(".{}", extension.to_string_lossy()))
+        .unwrap_or_default();
+    let mut index = 1;
+    loop {
+        let candidate = target.with_file_name(format!("{stem} ({index}){extension}"));
+        if !candidate.exists() {
+            return candidate;
+        }
+        index += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::archive::{new_writer, EntryMetadata};
+    use crate::config::Config;
+    use zip::write::SimpleFileOptions;
+    use zip::ZipWriter;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("mia-extract-{}-{name}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn extract(archive: PathBuf, dest: &Path) -> Result<(), Error> {
+        let password = PasswordArgs { password_env: None, password_file: None };
+        let mut extractor = Extractor::new(archive, Some(dest.to_path_buf()), OverwriteMode::Skip, false, vec![], vec![])?;
+        extractor.extract(&password)
+    }
+
+    #[test]
+    fn entry_paths_stay_inside() {
+        assert_eq!(safe_entry_path("src/main.rs"), Some(PathBuf::from("src").join("main.rs")));
+        assert_eq!(safe_entry_path("./a//b/"), Some(PathBuf::from("a").join("b")));
+        assert_eq!(safe_entry_path("src\\lib.rs"), Some(PathBuf::from("src").join("lib.rs")));
+        for name in ["../a", "a/../../b", "a\\..\\..\\b", "/etc/passwd", "\\Windows\\win.ini", "C:/a", "C:a", "a/D:/b", "a\0b", "", "./"] {
+            assert_eq!(safe_entry_path(name), None, "{name:?}");
+        }
+    }
+
+    #[test]
+    fn links_stay_inside() {
+        assert!(is_safe_link(Path::new("a/link"), "../b"));
+        assert!(is_safe_link(Path::new("link"), "a/b/../c"));
+        // Link paths come from `safe_entry_path`, backslashes already split
+        let path = safe_entry_path("a\\b\\link").unwrap();
+        assert!(is_safe_link(&path, "..\\c"));
+        assert!(!is_safe_link(&path, "..\\..\\..\\c"));
+        assert!(!is_safe_link(Path::new("link"), ".."));
+        assert!(!is_safe_link(Path::new("a/link"), "../../b"));
+        assert!(!is_safe_link(Path::new("link"), "a/../../b"));
+        assert!(!is_safe_link(Path::new("link"), "/etc/passwd"));
+        assert!(!is_safe_link(Path::new("link"), "\\Windows"));
+        assert!(!is_safe_link(Path::new("link"), "C:\\Windows"));
+        // Fine as text, the chain is caught by `check_links` once both exist
+        assert!(is_safe_link(Path::new("l2"), "."));
+        assert!(is_safe_link(Path::new("l1"), "l2/.."));
+    }
+
+    #[cfg(unix)]
+    fn links_archive(path: &Path, links: &[(&str, &str)], file: &str) -> PathBuf {
+        let mut zip = ZipWriter::new(File::create(path).unwrap());
+        let options = SimpleFileOptions::default();
+        for (name, target) in links {
+            zip.add_symlink(*name, *target, options).unwrap();
+        }
+        zip.start_file(file, options).unwrap();
+        zip.write_all(b"outside").unwrap();
+        zip.finish().unwrap();
+        path.to_path_buf()
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlink_chain_stays_inside() {
+        let dir = temp_dir("chain");
+        // Whatever the order of `l2 -> .` and `l1 -> l2/..`, `l1` would point at the parent of the destination
+        for (name, links) in [
+            ("forward", [("l2", "."), ("l1", "l2/..")]),
+            ("reversed", [("l1", "l2/.."), ("l2", ".")]),
+        ] {
+            let archive = links_archive(&dir.join(format!("{name}.zip")), &links, "other.txt");
+            let out = dir.join(name);
+            assert!(matches!(extract(archive, &out), Err(Error::UnsafeEntryPath(_))), "{name}");
+            assert!(!is_symlink(&out.join("l1")), "{name}");
+            assert!(is_symlink(&out.join("l2")), "{name}");
+
+            // A file under the link is written before any link exists, into a real folder
+            let archive = links_archive(&dir.join(format!("{name}-file.zip")), &links, "l1/escaped.txt");
+            let out = dir.join(format!("{name}-file"));
+            let _ = extract(archive, &out);
+            assert!(!dir.join("escaped.txt").exists(), "{name}");
+            assert!(out.join("l1").join("escaped.txt").is_file(), "{name}");
+        }
+
+        let links = [("lib.so", "lib.so.1"), ("lib.so.1", "./lib.so.1.2")];
+        let archive = links_archive(&dir.join("chain.zip"), &links, "lib.so.1.2");
+        let out = dir.join("chain");
+        extract(archive, &out).unwrap();
+        assert_eq!(fs::read(out.join("lib.so")).unwrap(), b"outside");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn files_under_existing_symlinks_are_refused() {
+        let dir = temp_dir("parent");
+        let out = dir.join("out");
+        fs::create_dir_all(&out).unwrap();
+        std::os::unix::fs::symlink("..", out.join("up")).unwrap();
+        let archive = links_archive(&dir.join("parent.zip"), &[("lib.so", "lib.so.1")], "up/file.txt");
+
+        assert!(matches!(extract(archive, &out), Err(Error::UnsafeEntryPath(_))));
+        assert!(!dir.join("file.txt").exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn restores_permissions_and_local_times() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = temp_dir("metadata");
+        let archive = dir.join("metadata.zip");
+        let mut writer = new_writer(&Config::default(), File::create(&archive).unwrap(), None).unwrap();
+        let metadata = EntryMetadata { mtime: Some(1_700_000_002), mode: Some(0o755) };
+        let entry = writer.encoder().encode("tool", b"#!/bin/sh\n".to_vec(), metadata).unwrap();
+        writer.write_entry(entry).unwrap();
+        writer.finish().unwrap();
+
+        let out = dir.join("out");
+        extract(archive, &out).unwrap();
+        let metadata = fs::metadata(out.join("tool")).unwrap();
+        assert_eq!(metadata.permissions().mode() & 0o7777, 0o755);
+        let modified = metadata.modified().unwrap().duration_since(SystemTime::UNIX_EPOCH).unwrap();
+        assert_eq!(modified.as_secs(), 1_700_000_002);
+    }
+}
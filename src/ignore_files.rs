@@ -0,0 +1,63 @@
+// Ignore File Manager
+
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use ignore::Match;
+use std::path::Path;
+use std::sync::Arc;
+
+// Per-directory ignore files, read in this order so later ones win on conflicting patterns
+const IGNORE_FILES: [&str; 2] = [".gitignore", ".miaignore"];
+
+// Matchers of a directory and its parents, outermost first. Cheap to clone, so every
+// branch of a parallel walk keeps its own.
+// Follows gitignore semantics: the deepest file with a matching pattern decides.
+#[derive(Clone)]
+pub struct IgnoreStack {
+    enabled: bool,
+    matchers: Vec<Arc<Gitignore>>,
+}
+
+impl IgnoreStack {
+    pub fn new(enabled: bool) -> Self {
+        IgnoreStack {
+            enabled,
+            matchers: Vec::new(),
+        }
+    }
+
+    // The stack to use for the entries of `dir`, with its ignore files read
+    pub fn child(&self, dir: &Path) -> IgnoreStack {
+        let mut stack = self.clone();
+        if !self.enabled {
+            return stack;
+        }
+        let mut builder = GitignoreBuilder::new(dir);
+        let mut found = false;
+        // `.git/info/exclude` applies to the repository it belongs to
+        let git_exclude = dir.join(".git").join("info").join("exclude");
+        let candidates = std::iter::once(git_exclude)
+            .chain(IGNORE_FILES.iter().map(|name| dir.join(name)));
+        for candidate in candidates {
+            if candidate.is_file() {
+                // Invalid lines are skipped, the same way git does
+                let _ = builder.add(candidate);
+                found = true;
+            }
+        }
+        if let Some(matcher) = found.then(|| builder.build().ok()).flatten() {
+            stack.matchers.push(Arc::new(matcher));
+        }
+        stack
+    }
+
+    pub fn is_ignored(&self, path: &Path, is_dir: bool) -> bool {
+        for matcher in self.matchers.iter().rev() {
+            match matcher.matched(path, is_dir) {
+                Match::Ignore(_) => return true,
+                Match::Whitelist(_) => return false,
+                Match::None => {}
+            }
+        }
+        false
+    }
+}
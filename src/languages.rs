@@ -1,52 +1,504 @@
+use crate::config::project_config_files;
+use crate::error::Error;
 use serde::Deserialize;
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::ops::AddAssign;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
 #[derive(Debug, Deserialize)]
 struct LanguageConfig {
-    extensions: Vec<String>,
+    #[serde(alias = "name")]
     language: Language,
+    #[serde(default)]
+    extensions: Vec<String>,
+    // Exact file names, such as `Makefile` or `Dockerfile`
+    #[serde(default)]
+    file_names: Vec<String>,
+    // Programs named by a `#!` line, without version numbers (`python` matches `python3.12`)
+    #[serde(default)]
+    interpreters: Vec<String>,
+    #[serde(flatten)]
+    syntax: Syntax,
 }
 
 #[derive(Debug, Deserialize)]
 struct Config {
+    #[serde(default)]
     languages: Vec<LanguageConfig>,
 }
 
-#[allow(clippy::upper_case_acronyms)]
-#[derive(Debug, Eq, PartialEq, Hash, Deserialize, Copy, Clone)]
-pub enum Language {
-    None,
-    Rust,
-    Python,
-    Java,
-    C,
-    CPP,
-    JavaScript,
-    HTML,
-    CSS,
-    PHP,
-    Swift,
-    Ruby,
-    Go,
-    Kotlin,
-    Scala,
-    TypeScript,
-    Lua,
-    Dart,
-    Markdown
+// A language name, either built in or defined in a languages file. Files without a language are `Other`.
+#[derive(Debug, Clone, Eq, PartialEq, Hash, Deserialize)]
+#[serde(from = "String")]
+pub struct Language(Arc<str>);
+
+impl Language {
+    pub fn other() -> Self {
+        Language(Arc::from(""))
+    }
+
+    pub fn is_other(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl From<String> for Language {
+    fn from(name: String) -> Self {
+        Language(Arc::from(name))
+    }
+}
+
+impl Display for Language {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if self.is_other() { write!(f, "Other") } else { write!(f, "{}", self.0) }
+    }
+}
+
+// Comment and string syntax of a language. Without any, every non-blank line is code.
+#[derive(Debug, Default, Clone, Deserialize)]
+pub struct Syntax {
+    #[serde(default)]
+    line_comments: Vec<String>,
+    // Start and end pairs, such as `/*` and `*/`
+    #[serde(default)]
+    block_comments: Vec<(String, String)>,
+    // Whether block comments can contain other block comments (Rust, Swift, Scala...)
+    #[serde(default)]
+    nested: bool,
+    // String delimiters. Comment markers inside strings are code. These strings end with their line.
+    #[serde(default)]
+    quotes: Vec<(String, String)>,
+    // Strings that can span lines, such as Python's `"""` or JavaScript's backticks
+    #[serde(default)]
+    multiline_quotes: Vec<(String, String)>,
+    // Whether `'"'` is a character and not the start of a string (Rust, C, Go...)
+    #[serde(default)]
+    char_literals: bool,
+}
+
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct LineStats {
+    pub code: i128,
+    pub comments: i128,
+    pub blanks: i128,
+}
+
+impl LineStats {
+    // Non-blank lines
+    pub fn lines(&self) -> i128 {
+        self.code + self.comments
+    }
+}
+
+impl AddAssign for LineStats {
+    fn add_assign(&mut self, other: Self) {
+        self.code += other.code;
+        self.comments += other.comments;
+        self.blanks += other.blanks;
+    }
+}
+
+impl Syntax {
+    // Classifies every line as code, comment or blank. A line with any code on it is code.
+    pub fn count(&self, text: &str) -> LineStats {
+        let mut stats = LineStats::default();
+        // Ends of the block comments currently open, innermost last
+        let mut comment_ends: Vec<&str> = Vec::new();
+        let mut string_end: Option<&str> = None;
+        let mut multiline = false;
+
+        for line in text.lines() {
+            let line = line.trim();
+            if line.is_empty() && string_end.is_none() {
+                stats.blanks += 1;
+                continue;
+            }
+
+            let mut has_code = string_end.is_some();
+            let mut has_comment = !comment_ends.is_empty();
+            let mut index = 0;
+            while index < line.len() {
+                let rest = &line[index..];
+
+                if let Some(end) = string_end {
+                    if let Some(escaped) = rest.strip_prefix('\\') {
+                        // Skips the escaped character whole, it can be more than a byte
+                        index += 1 + escaped.chars().next().map(char::len_utf8).unwrap_or(0);
+                        continue;
+                    } else if rest.starts_with(end) {
+                        string_end = None;
+                        index += end.len();
+                        continue;
+                    }
+                } else if let Some(end) = comment_ends.last().copied() {
+                    if rest.starts_with(end) {
+                        comment_ends.pop();
+                        index += end.len();
+                        continue;
+                    }
+                    if self.nested {
+                        if let Some((start, end)) = self.block_start(rest) {
+                            comment_ends.push(end);
+                            index += start.len();
+                            continue;
+                        }
+                    }
+                } else if let Some((start, end)) = self.block_start(rest) {
+                    // Checked before line comments, since Lua's `--[[` starts with `--`
+                    has_comment = true;
+                    comment_ends.push(end);
+                    index += start.len();
+                    continue;
+                } else if self.line_comments.iter().any(|comment| rest.starts_with(comment.as_str())) {
+                    has_comment = true;
+                    break;
+                } else if let Some(length) = self.char_literal(rest) {
+                    has_code = true;
+                    index += length;
+                    continue;
+                } else if let Some((start, end, spans_lines)) = self.quote_start(rest) {
+                    has_code = true;
+                    string_end = Some(end);
+                    multiline = spans_lines;
+                    index += start.len();
+                    continue;
+                } else if !rest.starts_with(char::is_whitespace) {
+                    has_code = true;
+                }
+
+                index += rest.chars().next().map(char::len_utf8).unwrap_or(1);
+            }
+            // An unclosed single line string is a syntax error, it shouldn't swallow the rest of the file
+            if !multiline {
+                string_end = None;
+            }
+
+            if has_code || !has_comment {
+                stats.code += 1;
+            } else {
+                stats.comments += 1;
+            }
+        }
+        stats
+    }
+
+    fn block_start(&self, rest: &str) -> Option<(&str, &str)> {
+        self.block_comments
+            .iter()
+            .find(|(start, _)| rest.starts_with(start.as_str()))
+            .map(|(start, end)| (start.as_str(), end.as_str()))
+    }
+
+    // Longest match first, so `"""` wins over `"`. The flag tells whether the string can span lines.
+    fn quote_start(&self, rest: &str) -> Option<(&str, &str, bool)> {
+        let single = self.quotes.iter().map(|quote| (quote, false));
+        let multi = self.multiline_quotes.iter().map(|quote| (quote, true));
+        single
+            .chain(multi)
+            .filter(|((start, _), _)| rest.starts_with(start.as_str()))
+            .max_by_key(|((start, _), _)| start.len())
+            .map(|((start, end), multiline)| (start.as_str(), end.as_str(), multiline))
+    }
+
+    // Length of a `'a'`, `'"'` or `'\n'` at the start of `rest`. Anything else, like a Rust lifetime, is `None`.
+    fn char_literal(&self, rest: &str) -> Option<usize> {
+        if !self.char_literals {
+            return None;
+        }
+        let inner = rest.strip_prefix('\'')?;
+        let first = inner.chars().next()?;
+        let length = if first == '\\' {
+            // The escaped character can be a `'` itself, `\u{1F600}` runs up to the next one
+            let escaped = inner[1..].chars().next()?;
+            let after = 1 + escaped.len_utf8();
+            after + inner[after..].find('\'')?
+        } else {
+            first.len_utf8()
+        };
+        inner[length..].starts_with('\'').then_some(length + 2)
+    }
 }
 
 const LANGUAGES_TOML: &str = include_str!("languages.toml");
+// Read from the config directory, next to the config file
+const USER_LANGUAGES_FILE: &str = "languages.toml";
 
 fn lang_config() -> Config {
     toml::from_str(LANGUAGES_TOML).expect("Failed to parse configuration")
 }
 
-pub fn detect_language(extension: &String) -> Language {
-    let config = lang_config();
-    config
-        .languages
-        .iter()
-        .find(|lang| lang.extensions.iter().any(|ext| ext == extension))
-        .map(|lang| lang.language)
-        .unwrap_or(Language::None)
-}
\ No newline at end of file
+// The built in languages with the user's definitions on top. Parsed once per run and shared by
+// every worker, lookups go through the indexes instead of scanning every definition.
+pub struct Languages {
+    // Later entries win when an extension or file name is claimed twice
+    languages: Vec<LanguageConfig>,
+    // Positions in `languages`, keyed by lowercased extension, file name and interpreter
+    by_extension: HashMap<String, usize>,
+    by_file_name: HashMap<String, usize>,
+    by_interpreter: HashMap<String, usize>,
+    modelines: bool,
+}
+
+// What the detection step found out about a file
+pub struct Detection<'a> {
+    pub language: Language,
+    pub syntax: &'a Syntax,
+    // Lockfiles, minified and generated code or vendored dependencies, reported apart from the rest
+    pub generated: bool,
+}
+
+impl Languages {
+    // `project` is the folder the run is about, if any
+    pub fn load(project: Option<&Path>, modelines: bool) -> Result<Languages, Error> {
+        let mut languages = Languages {
+            languages: lang_config().languages,
+            by_extension: HashMap::new(),
+            by_file_name: HashMap::new(),
+            by_interpreter: HashMap::new(),
+            modelines,
+        };
+        if let Some(file) = user_languages_file() {
+            languages.extend_from(&file)?;
+        }
+        // Project files take precedence over every other file, the innermost one last
+        for file in project.map(project_config_files).unwrap_or_default() {
+            languages.extend_from(&file)?;
+        }
+        languages.build_indexes();
+        Ok(languages)
+    }
+
+    // In definition order, so later definitions overwrite earlier ones
+    fn build_indexes(&mut self) {
+        for (index, lang) in self.languages.iter().enumerate() {
+            for extension in &lang.extensions {
+                self.by_extension.insert(extension.clone(), index);
+            }
+            for file_name in &lang.file_names {
+                self.by_file_name.insert(file_name.to_ascii_lowercase(), index);
+            }
+            for interpreter in &lang.interpreters {
+                self.by_interpreter.insert(interpreter.clone(), index);
+            }
+        }
+    }
+
+    // A definition with the name of an existing language replaces it
+    fn extend_from(&mut self, file: &Path) -> Result<(), Error> {
+        if !file.is_file() {
+            return Ok(());
+        }
+        let text = fs::read_to_string(file)?;
+        let config: Config = toml::from_str(&text)
+            .map_err(|error| Error::Custom(format!("Invalid languages in {file:?}: {error}")))?;
+        for mut language in config.languages {
+            if language.language.is_other() {
+                return Err(Error::Custom(format!("Invalid languages in {file:?}: a language has no name")));
+            }
+            for extension in language.extensions.iter_mut() {
+                *extension = extension.trim_start_matches('.').to_ascii_lowercase();
+            }
+            let name = language.language.0.to_ascii_lowercase();
+            self.languages.retain(|existing| existing.language.0.to_ascii_lowercase() != name);
+            self.languages.push(language);
+        }
+        Ok(())
+    }
+
+    // `path` is relative to the archive root. Checked in order: a vim or emacs modeline (if enabled),
+    // the exact file name, the extension, then the `#!` line.
+    pub fn detect(&self, path: &Path, text: &str) -> Detection<'_> {
+        let file_name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().to_ascii_lowercase())
+            .unwrap_or_default();
+        let extension = path
+            .extension()
+            .map(|extension| extension.to_string_lossy().to_ascii_lowercase())
+            .unwrap_or_default();
+
+        let modeline = self.modelines.then(|| modeline(text)).flatten();
+        let language = modeline
+            .and_then(|mode| self.languages.iter().rev().find(|lang| lang.is_called(&mode)))
+            .or_else(|| self.lookup(&self.by_file_name, &file_name))
+            .or_else(|| self.lookup(&self.by_extension, &extension))
+            .or_else(|| self.lookup(&self.by_interpreter, shebang_interpreter(text)?));
+
+        let (language, syntax) = language
+            .map(|lang| (lang.language.clone(), &lang.syntax))
+            .unwrap_or((Language::other(), &NO_SYNTAX));
+        Detection {
+            language,
+            syntax,
+            generated: is_generated(path, &file_name, &extension, text),
+        }
+    }
+
+    fn lookup(&self, index: &HashMap<String, usize>, key: &str) -> Option<&LanguageConfig> {
+        index.get(key).map(|&position| &self.languages[position])
+    }
+}
+
+impl LanguageConfig {
+    // Modelines name a language by its name, an extension or an interpreter (`ft=python`, `ft=sh`)
+    fn is_called(&self, mode: &str) -> bool {
+        self.language.0.eq_ignore_ascii_case(mode)
+            || self.extensions.iter().any(|extension| extension == mode)
+            || self.interpreters.iter().any(|interpreter| interpreter == mode)
+    }
+}
+
+// Only the first and last few lines can hold a modeline, the same as in vim
+const MODELINE_LINES: usize = 5;
+
+// `vim: set ft=python:`, `vi: syntax=sh` or `-*- mode: ruby -*-`, lowercased
+fn modeline(text: &str) -> Option<String> {
+    let lines: Vec<&str> = text.lines().collect();
+    let head = lines.iter().take(MODELINE_LINES);
+    let tail = lines.iter().skip(MODELINE_LINES).rev().take(MODELINE_LINES);
+    head.chain(tail)
+        .find_map(|line| emacs_mode(line).or_else(|| vim_filetype(line)))
+        .map(|mode| mode.to_ascii_lowercase())
+}
+
+fn emacs_mode(line: &str) -> Option<&str> {
+    let start = line.find("-*-")? + 3;
+    let end = start + line[start..].find("-*-")?;
+    let inner = line[start..end].trim();
+    if !inner.contains(':') {
+        return Some(inner);
+    }
+    inner
+        .split(';')
+        .find_map(|variable| variable.trim().strip_prefix("mode:"))
+        .map(str::trim)
+}
+
+fn vim_filetype(line: &str) -> Option<&str> {
+    let start = ["vim:", "vi:", "ex:"].iter().find_map(|marker| {
+        let index = line.find(marker)?;
+        // `vi:` has to start a word, so `navi:` isn't one
+        let starts_word = line[..index].chars().next_back().is_none_or(char::is_whitespace);
+        starts_word.then_some(index + marker.len())
+    })?;
+    line[start..]
+        .split(|c: char| c.is_whitespace() || c == ':')
+        .find_map(|option| {
+            option
+                .strip_prefix("ft=")
+                .or_else(|| option.strip_prefix("filetype="))
+                .or_else(|| option.strip_prefix("syntax="))
+        })
+}
+
+// `#!/usr/bin/env python3 -u` gives `python`
+fn shebang_interpreter(text: &str) -> Option<&str> {
+    let line = text.lines().next()?.strip_prefix("#!")?;
+    let mut parts = line.split_whitespace();
+    let mut program = parts.next()?.rsplit('/').next()?;
+    if program == "env" {
+        program = parts.find(|part| !part.starts_with('-') && !part.contains('='))?;
+    }
+    Some(program.trim_end_matches(|c: char| c.is_ascii_digit() || c == '.'))
+}
+
+// Folders of third party code that's checked in
+const VENDOR_FOLDERS: [&str; 4] = ["vendor", "node_modules", "third_party", "bower_components"];
+const LOCKFILES: [&str; 4] = ["package-lock.json", "npm-shrinkwrap.json", "pnpm-lock.yaml", "go.sum"];
+// Markers that code generators put in the first lines of a file
+const GENERATED_MARKERS: [&str; 4] = ["@generated", "code generated", "auto-generated", "autogenerated"];
+// Average line length above which JavaScript or CSS counts as minified
+const MINIFIED_LINE_LENGTH: usize = 250;
+
+// `file_name` and `extension` are lowercased
+fn is_generated(path: &Path, file_name: &str, extension: &str, text: &str) -> bool {
+    let vendored = path
+        .parent()
+        .map(|parent| parent.iter().any(|folder| VENDOR_FOLDERS.contains(&folder.to_string_lossy().as_ref())))
+        .unwrap_or(false);
+    let lockfile = extension == "lock" || LOCKFILES.contains(&file_name);
+    let minified = file_name.contains(".min.")
+        || (matches!(extension, "js" | "css") && text.len() / text.lines().count().max(1) > MINIFIED_LINE_LENGTH);
+    let marked = text.lines().take(MODELINE_LINES).any(|line| {
+        let line = line.to_ascii_lowercase();
+        GENERATED_MARKERS.iter().any(|marker| line.contains(marker))
+    });
+    vendored || lockfile || minified || marked
+}
+
+static NO_SYNTAX: Syntax = Syntax {
+    line_comments: Vec::new(),
+    block_comments: Vec::new(),
+    nested: false,
+    quotes: Vec::new(),
+    multiline_quotes: Vec::new(),
+    char_literals: false,
+};
+
+fn user_languages_file() -> Option<PathBuf> {
+    let config_file = confy::get_configuration_file_path("mia", None).ok()?;
+    Some(config_file.parent()?.join(USER_LANGUAGES_FILE))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn syntax(name: &str) -> Syntax {
+        let language = lang_config().languages.into_iter().find(|lang| &*lang.language.0 == name);
+        language.expect("built in language").syntax
+    }
+
+    fn stats(code: i128, comments: i128, blanks: i128) -> LineStats {
+        LineStats { code, comments, blanks }
+    }
+
+    #[test]
+    fn counts_code_comments_and_blanks() {
+        let text = "// header\n\nfn main() { /* inline */ }\n/* one\n   two */\nlet x = 1; // trailing\n";
+        assert_eq!(syntax("Rust").count(text), stats(2, 3, 1));
+    }
+
+    #[test]
+    fn nested_block_comments() {
+        let text = "/* outer /* inner */\nstill comment */\ncode();\n";
+        assert_eq!(syntax("Rust").count(text), stats(1, 2, 0));
+    }
+
+    #[test]
+    fn comment_markers_inside_strings_are_code() {
+        let text = "url = \"http://example.com\"\n# comment\n";
+        assert_eq!(syntax("Python").count(text), stats(1, 1, 0));
+    }
+
+    #[test]
+    fn escapes_before_multibyte_characters() {
+        assert_eq!(syntax("Python").count("s = \"caf\\é\"\n# done\n"), stats(1, 1, 0));
+        assert_eq!(syntax("Rust").count("let s = \"\\日本\"; // x\n"), stats(1, 0, 0));
+    }
+
+    #[test]
+    fn unclosed_strings_end_with_their_line() {
+        let text = "s = 'it\n# comment\n";
+        assert_eq!(syntax("Python").count(text), stats(1, 1, 0));
+    }
+
+    #[test]
+    fn multiline_strings_span_lines() {
+        let text = "s = \"\"\"\n# not a comment\n\n\"\"\"\n# comment\n";
+        assert_eq!(syntax("Python").count(text), stats(4, 1, 0));
+        let text = "let s = \"a\n// not a comment\";\n";
+        assert_eq!(syntax("Rust").count(text), stats(2, 0, 0));
+    }
+
+    #[test]
+    fn char_literals_are_not_strings() {
+        let text = "let quote = '\"';\n// comment\nlet tick = '\\'';\nfn f<'a>(x: &'a str) {}\n/* c */\n";
+        assert_eq!(syntax("Rust").count(text), stats(3, 2, 0));
+        let text = "char c = '\\u00e9'; /* c */\n// comment\n";
+        assert_eq!(syntax("C").count(text), stats(1, 1, 0));
+    }
+}
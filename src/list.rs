@@ -0,0 +1,85 @@
+// Archive Lister
+
+use crate::config::Config;
+use crate::dir_manager::{count_lines, LineTotals};
+use crate::error::Error;
+use crate::languages::Languages;
+use crate::password::PasswordArgs;
+use inline_colorization::*;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use zip::result::ZipError;
+use zip::ZipArchive;
+
+// Prints every entry, then runs the language statistics on the contents without extracting them
+pub fn list_archive(archive: &Path, config: &Config, password_args: &PasswordArgs) -> Result<(), Error> {
+    if !archive.is_file() {
+        return Err(Error::PathNotFound);
+    }
+    let mut zip = ZipArchive::new(File::open(archive)?)?;
+    let password = password_args.read_for(&mut zip)?;
+    let languages = Languages::load(None, config.detect_modelines)?;
+
+    let mut lines = LineTotals::default();
+    let mut count = 0;
+    let mut total_size = 0;
+    let mut total_compressed = 0;
+
+    println!("{:>12} {:>12} {:<10} {:<16} Name", "Size", "Compressed", "Method", "Modified");
+    for index in 0..zip.len() {
+        let mut entry = match &password {
+            Some(password) => zip.by_index_decrypt(index, password.as_str().as_bytes()),
+            None => zip.by_index(index),
+        }
+        .map_err(|error| match error {
+            ZipError::InvalidPassword => "Incorrect password".into(),
+            error => Error::ZipFileFail(error),
+        })?;
+
+        let modified = entry
+            .last_modified()
+            .map(|time| {
+                format!(
+                    "{:04}-{:02}-{:02} {:02}:{:02}",
+                    time.year(),
+                    time.month(),
+                    time.day(),
+                    time.hour(),
+                    time.minute()
+                )
+            })
+            .unwrap_or_default();
+        let name = entry.name().to_string();
+
+        if entry.is_dir() {
+            println!("{:>12} {:>12} {:<10} {modified:<16} {color_cyan}{name}{color_reset}", "-", "-", "-");
+            continue;
+        }
+
+        let mut content = Vec::with_capacity(entry.size() as usize);
+        entry.read_to_end(&mut content)?;
+        let line_count = count_lines(&languages, Path::new(&name), &content);
+        lines.add(&line_count);
+        let lines_text = line_count.describe();
+        println!(
+            "{:>12} {:>12} {:<10} {modified:<16} {color_cyan}{name}{color_reset} \
+            {color_yellow}{lines_text}{color_reset}",
+            entry.size(),
+            entry.compressed_size(),
+            format!("{:?}", entry.compression()),
+        );
+
+        count += 1;
+        total_size += entry.size();
+        total_compressed += entry.compressed_size();
+    }
+
+    println!(
+        "Listed {color_cyan}{count}{color_reset} files, {color_cyan}{total_size}{color_reset} bytes \
+        ({color_cyan}{total_compressed}{color_reset} compressed, {color_cyan}{}{color_reset} lines)",
+        lines.total().lines()
+    );
+    lines.print();
+    Ok(())
+}
@@ -1,25 +1,38 @@
 // Mia Command Line Interface
 // Author: Derek Blaney
 
+mod archive;
 mod cli;
 mod config;
 mod dir_manager;
 mod error;
+mod ignore_files;
+mod extract;
 mod release;
 mod languages;
+mod list;
+mod manifest;
+mod password;
+mod patterns;
+mod report;
+mod source;
+mod stats;
+mod verify;
+mod versions;
 
-use std::fs;
-use std::fs::{File, OpenOptions};
-use std::path::PathBuf;
+use std::path::Path;
 use std::string::ToString;
 use std::time::Instant;
-use crate::cli::ConfigAction;
+use crate::archive::ArchiveFormat;
+use crate::cli::{ConfigAction, ProfileAction};
 use crate::dir_manager::Directory;
 use crate::error::Error;
+use crate::extract::Extractor;
+use crate::report::ReportOptions;
 use clap::Parser;
 use cli::{Args, Zip};
 use inline_colorization::*;
-use crate::release::{download_asset, get_download_link_for_asset, get_latest_release}; // This IS used. IDE doesn't detect it.
+use crate::release::Updater;
 
 fn main() -> Result<(), Error> {
     let args = Args::parse();
@@ -34,16 +47,84 @@ fn main() -> Result<(), Error> {
             include,
             out,
             default_out,
+            format,
+            compression,
+            level,
+            no_ignore,
+            symlinks,
+            reproducible,
+            encrypt,
+            password,
+            report,
+            report_file,
+            manifest,
+            dry_run,
+            profile,
         } => {
+            let (mut config, mut sources) = config.layered(Path::new(&location))?;
+            if let Some(profile) = profile {
+                config.apply_profile(&profile, &mut sources)?;
+            }
             let mut output_dir = if out.is_none() {
                 config.output_dir.clone()
             } else {
                 out
             };
             if default_out { output_dir = None; }
-            let mut directory =
-                Directory::new(&location, &name, config, verbose, exclude, include, output_dir)?;
-            directory.zip_it()?;
+            if let Some(format) = format { config.format = format; }
+            if let Some(compression) = compression { config.compression = compression; }
+            if level.is_some() { config.compression_level = level; }
+            if no_ignore { config.use_ignore_files = false; }
+            if reproducible { config.reproducible = true; }
+            if let Some(symlinks) = symlinks { config.symlinks = symlinks; }
+            // A password source means the archive was meant to be encrypted, it's never written in the clear
+            let password = if encrypt || password.is_set() {
+                if config.format != ArchiveFormat::Zip {
+                    return Err("Encryption is only supported for zip archives".into());
+                }
+                if config.reproducible {
+                    return Err("Encrypted archives can't be reproducible, AES uses a random salt".into());
+                }
+                // Nothing is written by a dry run, so there's nothing to ask a password for
+                if dry_run { None } else { Some(password.read(true)?) }
+            } else { None };
+            let mut directory = Directory::new(
+                &location, &name, config, verbose, exclude, include, output_dir, password,
+            )?;
+            if let Some(format) = manifest {
+                directory = directory.with_manifest(format);
+            }
+            if let Some(format) = report {
+                directory = directory.with_report(ReportOptions { format, file: report_file });
+            }
+            if dry_run {
+                directory.dry_run()?;
+            } else {
+                directory.zip_it()?;
+            }
+        }
+        Zip::Extract {
+            archive,
+            dest,
+            verbose,
+            exclude,
+            include,
+            overwrite,
+            password,
+        } => {
+            let mut extractor = Extractor::new(archive, dest, overwrite, verbose, exclude, include)?;
+            extractor.extract(&password)?;
+        }
+        Zip::List { archive, password } => {
+            list::list_archive(&archive, &config, &password)?;
+        }
+        Zip::Verify { archive, password } => {
+            verify::verify_archive(&archive, &password)?;
+        }
+        Zip::Stats { location, exclude, include, no_ignore, top, depth } => {
+            let (mut config, _) = config.layered(Path::new(&location))?;
+            if no_ignore { config.use_ignore_files = false; }
+            stats::stats_directory(Path::new(&location), &config, &exclude, &include, top, depth)?;
         }
         Zip::Config { action } => {
             match action {
@@ -68,7 +149,66 @@ fn main() -> Result<(), Error> {
                     println!("Successfully removed value {color_cyan}`{string_value}`{color_reset} \
                     from {color_cyan}`{string_key}`{color_reset}");
                 }
-                ConfigAction::List => {
+                ConfigAction::List { effective: true, profile } => {
+                    // Only shown, the global config is saved untouched below
+                    let dir = std::env::current_dir()?;
+                    let (mut effective, mut sources) = config.clone().layered(&dir)?;
+                    if let Some(profile) = profile {
+                        effective.apply_profile(&profile, &mut sources)?;
+                    }
+                    print_pretty_header("Effective Config", 4);
+                    for (key, value) in effective.entries() {
+                        let source = sources.get(key).unwrap_or(&config::Source::Global);
+                        println!(" - {key}: {value} {color_cyan}({source}){color_reset}");
+                    }
+                    for file in config::project_config_files(&dir) {
+                        println!(" ↳ {color_cyan}{}{color_reset}", file.display());
+                    }
+                }
+                ConfigAction::Profile { action: ProfileAction::Add {
+                    name, naming, out, format, compression, level, file_name, folder_name, extension,
+                } } => {
+                    let profile = config::Profile {
+                        naming,
+                        output_dir: out,
+                        format,
+                        compression,
+                        compression_level: level,
+                        blacklisted_file_names: file_name,
+                        blacklisted_folder_names: folder_name,
+                        blacklisted_file_extensions: extension,
+                    };
+                    config.profiles.insert(name.clone(), profile);
+                    println!("Successfully added profile {color_cyan}`{name}`{color_reset}");
+                }
+                ConfigAction::Profile { action: ProfileAction::Remove { name } } => {
+                    if config.profiles.remove(&name).is_none() {
+                        return Err(Error::ConfigActionError(format!("Couldn't find profile `{name}`")));
+                    }
+                    println!("Successfully removed profile {color_cyan}`{name}`{color_reset}");
+                }
+                ConfigAction::Profile { action: ProfileAction::Show { name } } => {
+                    // Includes profiles from the current folder's .mia.toml files
+                    let (effective, _) = config.clone().layered(&std::env::current_dir()?)?;
+                    let profiles: Vec<(&String, &config::Profile)> = match &name {
+                        Some(name) => match effective.profiles.get_key_value(name) {
+                            Some(profile) => vec![profile],
+                            None => return Err(Error::ConfigActionError(format!("Couldn't find profile `{name}`"))),
+                        },
+                        None => effective.profiles.iter().collect(),
+                    };
+                    print_pretty_header("Profiles", 4);
+                    if profiles.is_empty() {
+                        println!(" ↳ {color_cyan}mia config profile add <name> [options]{color_reset}");
+                    }
+                    for (name, profile) in profiles {
+                        println!(" - {color_cyan}{name}{color_reset}");
+                        for (key, value) in profile.entries() {
+                            println!("   {key}: {value}");
+                        }
+                    }
+                }
+                ConfigAction::List { effective: false, .. } => {
                     print_pretty_header("Config List", 4);
                     println!(" - Naming: {}", config.naming);
                     println!(" ↳ {color_cyan}mia config set naming <format>{color_reset}");
@@ -76,6 +216,29 @@ fn main() -> Result<(), Error> {
                         .to_string
                         ()));
                     println!(" ↳ {color_cyan}mia config set output_dir <format>{color_reset}");
+                    println!(" - Format: {:?}", config.format);
+                    println!(" ↳ {color_cyan}mia config set format <zip/tar/tar-gz/tar-xz/tar-zst>{color_reset}");
+                    println!(" - Compression: {:?}", config.compression);
+                    println!(" ↳ {color_cyan}mia config set compression <stored/deflate/bzip2/zstd>{color_reset}");
+                    println!(" - Compression level: {}", config.compression_level
+                        .map(|level| level.to_string())
+                        .unwrap_or("Default".to_string()));
+                    println!(" ↳ {color_cyan}mia config set compression_level <level>{color_reset}");
+                    println!(" - Use ignore files: {}", config.use_ignore_files);
+                    println!(" ↳ {color_cyan}mia config set use_ignore_files <true/false>{color_reset}");
+                    println!(" - Symlinks: {:?}", config.symlinks);
+                    println!(" ↳ {color_cyan}mia config set symlinks <follow/store/skip>{color_reset}");
+                    println!(" - Reproducible: {}", config.reproducible);
+                    println!(" ↳ {color_cyan}mia config set reproducible <true/false>{color_reset}");
+                    println!(" - Detect modelines: {}", config.detect_modelines);
+                    println!(" ↳ {color_cyan}mia config set detect_modelines <true/false>{color_reset}");
+                    println!(" - Keep versions: {}", config.keep_versions);
+                    println!(" ↳ {color_cyan}mia config set keep_versions <count>{color_reset}");
+                    println!(" - Channel: {:?}", config.channel);
+                    println!(" ↳ {color_cyan}mia config set channel <stable/prerelease>{color_reset}");
+                    println!(" - Release source: {}", config.release_source.clone()
+                        .unwrap_or(source::DEFAULT_SOURCE.to_string()));
+                    println!(" ↳ {color_cyan}mia config set release_source <github:owner/repo/URL>{color_reset}");
                     println!(
                         " - Blacklisted file names: {:?}",
                         config.blacklisted_file_names
@@ -91,52 +254,81 @@ fn main() -> Result<(), Error> {
                         config.blacklisted_file_extensions
                     );
                     println!(" ↳ {color_cyan}mia config add/remove blacklisted_file_extensions <value>{color_reset}");
+                    println!(
+                        " - Stored (uncompressed) file extensions: {:?}",
+                        config.stored_extensions
+                    );
+                    println!(" ↳ {color_cyan}mia config add/remove stored_extensions <value>{color_reset}");
                 }
             }
 
             // Save config
             confy::store("mia", None, config).map_err(Error::Config)?;
         },
-        Zip::Update { version } => {
-            let mut ver = match &version {
-                None => { get_latest_release()? }
-                Some(version) => { version.to_string() }
-            };
-
-            println!("Updating Mia to {color_bright_green}{ver}{color_reset}");
-
-            let start = Instant::now();
-            let mut path = std::env::current_exe()?;
-            path.pop();
-            path.push("mia-tmp.exe");
-
-            println!("{path:?}");
-            let mut file = find_or_create_file(&path)?;
-            println!("Downloading asset...");
-            let download_link = get_download_link_for_asset(&ver)?;
-            download_asset(&download_link, &mut file)?;
-            println!("Downloaded asset.");
-            println!("Renaming mia.exe to mia-old.exe");
-            let mut new_path = path.clone();
-            new_path.pop();
-            new_path.push("mia.exe");
-
-            let mut old_path = path.clone();
-            old_path.pop();
-            old_path.push("mia-old.exe");
-
-            fs::rename(&new_path, &old_path)?;
-            println!("Renaming mia-tmp.exe to mia.exe");
-            fs::rename(&path, &new_path)?;
-            let elapsed = start.elapsed().as_millis();
-
-            ver = match &version {
-                None => { get_latest_release()? }
-                Some(version) => { version.to_string() }
-            };
-
-            println!("Mia updated in {color_bright_green}{elapsed}ms{color_reset} to {color_bright_green}{ver}{color_reset} (Run terminal as administrator if this didn't work)");
+        Zip::Update { version, rollback, list_installed, check } => {
+            let (config, _) = config.with_env()?;
+            let updater = Updater::new(&config)?;
+            if check {
+                let check = updater.check()?;
+                if check.is_outdated() {
+                    println!(
+                        "Mia {color_bright_green}{}{color_reset} is available (current {color_cyan}{}{color_reset})",
+                        check.latest.tag_name, check.current
+                    );
+                    println!(" ↳ {color_cyan}mia update{color_reset}");
+                } else {
+                    println!(
+                        "Mia {color_cyan}{}{color_reset} is up to date (latest {})",
+                        check.current, check.latest.tag_name
+                    );
+                }
+            } else if list_installed {
+                print_pretty_header("Installed Versions", 4);
+                println!(" - {color_cyan}{}{color_reset} (current)", env!("CARGO_PKG_VERSION"));
+                for installed in updater.versions().list()? {
+                    let retired: chrono::DateTime<chrono::Local> = installed.retired().into();
+                    println!(
+                        " - {color_cyan}{}{color_reset} replaced {} ({})",
+                        installed.version,
+                        retired.format("%Y-%m-%d %H:%M"),
+                        stats::format_size(installed.size)
+                    );
+                }
+                println!(" ↳ {color_cyan}mia update --rollback{color_reset}");
+            } else if rollback {
+                let version = updater.rollback()?;
+                println!("Mia rolled back to {color_bright_green}{version}{color_reset}");
+            } else {
+                let start = Instant::now();
+                let version = updater.update(version.as_deref())?;
+                let elapsed = start.elapsed().as_millis();
+                println!("Mia updated in {color_bright_green}{elapsed}ms{color_reset} to {color_bright_green}{version}{color_reset}");
+            }
         },
+        Zip::Releases { limit, notes } => {
+            let (config, _) = config.with_env()?;
+            let updater = Updater::new(&config)?;
+            let current = release::parse_version(updater.version());
+            let releases = updater.releases(limit)?;
+            print_pretty_header("Releases", 4);
+            for release in releases {
+                let date = release.published_at.as_deref().and_then(|date| date.get(..10)).unwrap_or("unpublished");
+                let mut labels = String::new();
+                if release.prerelease {
+                    labels.push_str(" (prerelease)");
+                }
+                if release.version().is_some() && release.version() == current {
+                    labels.push_str(" (current)");
+                }
+                println!(" - {color_cyan}{}{color_reset} {date}{labels}", release.tag_name);
+                let body = release.body.as_deref().unwrap_or_default().trim();
+                let lines: Vec<&str> = body.lines().filter(|line| !line.trim().is_empty()).collect();
+                let shown = if notes { lines.len() } else { lines.len().min(1) };
+                for line in &lines[..shown] {
+                    println!("   {}", line.trim_end());
+                }
+            }
+        }
         Zip::Version => {
             let version = env!("CARGO_PKG_VERSION");
             println!("Current version: {color_cyan}{}{color_reset}", version);
@@ -152,11 +344,3 @@ pub fn print_pretty_header(text: &str, padding: usize) {
     println!("{padding_text}{color_cyan}{text}{color_reset}{padding_text}");
     println!("{}", "=".repeat(text.len() + (padding * 2)));
 }
-
-fn find_or_create_file(file_path: &PathBuf) -> std::io::Result<File> {
-    OpenOptions::new()
-        .read(true)
-        .write(true)
-        .create(true) // Create the file if it doesn't exist
-        .open(file_path)
-}
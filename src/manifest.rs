@@ -0,0 +1,109 @@
+// Manifest Manager
+
+use crate::error::Error;
+use chrono::DateTime;
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq, ValueEnum)]
+pub enum ManifestFormat {
+    /// MANIFEST.json, with sizes, modification times and languages
+    #[default]
+    Json,
+    /// SHA256SUMS, readable by `sha256sum -c`
+    Sha256sums,
+}
+
+impl ManifestFormat {
+    pub fn file_name(&self) -> &'static str {
+        match self {
+            ManifestFormat::Json => "MANIFEST.json",
+            ManifestFormat::Sha256sums => "SHA256SUMS",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ManifestEntry {
+    pub path: String,
+    pub sha256: String,
+    pub size: u64,
+    // Seconds since the Unix epoch
+    pub mtime: Option<i64>,
+    pub language: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Manifest {
+    pub mia_version: String,
+    // RFC 3339
+    pub created: String,
+    pub files: Vec<ManifestEntry>,
+    // `created` in seconds, for the manifest entry's own modification time
+    #[serde(skip)]
+    pub created_at: i64,
+}
+
+impl Manifest {
+    // `created` is pinned for reproducible archives
+    pub fn new(created: i64) -> Self {
+        Manifest {
+            mia_version: env!("CARGO_PKG_VERSION").to_string(),
+            created: DateTime::from_timestamp(created, 0)
+                .map(|time| time.to_rfc3339())
+                .unwrap_or_default(),
+            files: Vec::new(),
+            created_at: created,
+        }
+    }
+
+    pub fn render(&self, format: ManifestFormat) -> Result<Vec<u8>, Error> {
+        match format {
+            ManifestFormat::Json => Ok(serde_json::to_vec_pretty(self)?),
+            ManifestFormat::Sha256sums => Ok(self
+                .files
+                .iter()
+                .map(|file| format!("{}  {}\n", file.sha256, file.path))
+                .collect::<String>()
+                .into_bytes()),
+        }
+    }
+
+    // Expected hash and size (if known) of every file, read back from an archive entry
+    pub fn parse(format: ManifestFormat, content: &[u8]) -> Result<HashMap<String, (String, Option<u64>)>, Error> {
+        match format {
+            ManifestFormat::Json => {
+                let manifest: Manifest = serde_json::from_slice(content)?;
+                Ok(manifest
+                    .files
+                    .into_iter()
+                    .map(|file| (file.path, (file.sha256, Some(file.size))))
+                    .collect())
+            }
+            ManifestFormat::Sha256sums => {
+                let text = std::str::from_utf8(content).map_err(|_| "SHA256SUMS isn't valid UTF-8")?;
+                text.lines()
+                    .filter(|line| !line.trim().is_empty())
+                    .map(|line| {
+                        // `<hash>  <path>`, or `<hash> *<path>` for binary mode
+                        let (hash, path) = line
+                            .split_once(' ')
+                            .ok_or_else(|| Error::Custom(format!("Invalid SHA256SUMS line: {line:?}")))?;
+                        let path = path.strip_prefix([' ', '*']).unwrap_or(path);
+                        Ok((path.to_string(), (hash.to_ascii_lowercase(), None)))
+                    })
+                    .collect()
+            }
+        }
+    }
+}
+
+pub fn sha256_hex(content: &[u8]) -> String {
+    hex(&Sha256::digest(content))
+}
+
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
@@ -0,0 +1,80 @@
+// Password Manager
+
+use crate::error::Error;
+use clap::Args;
+use std::fmt::{Debug, Formatter};
+use std::fs;
+use std::io::{Read, Seek};
+use std::path::PathBuf;
+
+#[derive(Args, Debug)]
+pub struct PasswordArgs {
+    /// Read the archive password from this environment variable
+    #[arg(long, value_name = "VAR")]
+    pub password_env: Option<String>,
+
+    /// Read the archive password from the first line of this file
+    #[arg(long, value_name = "PATH")]
+    pub password_file: Option<PathBuf>,
+}
+
+// Wraps the plaintext so it can't leak through `{:?}` in verbose output
+pub struct Password(String);
+
+impl Password {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Debug for Password {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "********")
+    }
+}
+
+impl PasswordArgs {
+    // Whether a password source was given explicitly
+    pub fn is_set(&self) -> bool {
+        self.password_env.is_some() || self.password_file.is_some()
+    }
+
+    // Only asks when an entry is encrypted or a password source was given explicitly
+    pub fn read_for<R: Read + Seek>(
+        &self,
+        zip: &mut zip::ZipArchive<R>,
+    ) -> Result<Option<Password>, Error> {
+        let encrypted = (0..zip.len()).any(|index| {
+            zip.by_index_raw(index)
+                .map(|entry| entry.encrypted())
+                .unwrap_or(false)
+        });
+        if encrypted || self.is_set() {
+            Ok(Some(self.read(false)?))
+        } else {
+            Ok(None)
+        }
+    }
+
+    // Env var first, then file, then an interactive prompt. `confirm` asks twice when prompting.
+    pub fn read(&self, confirm: bool) -> Result<Password, Error> {
+        let password = if let Some(var) = &self.password_env {
+            std::env::var(var)
+                .map_err(|_| Error::Custom(format!("Environment variable `{var}` is not set")))?
+        } else if let Some(path) = &self.password_file {
+            let content = fs::read_to_string(path)?;
+            content.lines().next().unwrap_or_default().to_string()
+        } else {
+            let password = rpassword::prompt_password("Password: ")?;
+            if confirm && rpassword::prompt_password("Confirm password: ")? != password {
+                return Err("Passwords do not match".into());
+            }
+            password
+        };
+
+        if password.is_empty() {
+            return Err("Password can't be empty".into());
+        }
+        Ok(Password(password))
+    }
+}
@@ -0,0 +1,252 @@
+// Pattern Manager
+
+use crate::config::Config;
+use crate::error::Error;
+use globset::{GlobBuilder, GlobMatcher};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use ignore::Match;
+use serde::Serialize;
+use std::fmt::{Display, Formatter};
+use std::path::{Path, PathBuf};
+
+// How a bare word (no glob characters, no `/`) is read, so older configs keep their meaning
+#[derive(Copy, Clone)]
+enum PatternKind {
+    // `target` matches a file or folder named `target`, or any `*.target` file
+    Name,
+    // `bin` matches folders named `bin`
+    FolderName,
+    // `readme` matches `readme` and `readme.*`
+    FileName,
+    // `zip` matches `*.zip`
+    Extension,
+}
+
+// A list of gitignore-style patterns. Patterns containing a `/` are relative to the root,
+// later patterns override earlier ones and `!pattern` negates.
+pub struct Patterns {
+    matcher: Gitignore,
+}
+
+impl Patterns {
+    fn new(root: &Path, patterns: &[String], kind: PatternKind) -> Result<Self, Error> {
+        let mut builder = GitignoreBuilder::new(root);
+        builder
+            .case_insensitive(true)
+            .map_err(|error| Error::InvalidPattern(error.to_string()))?;
+        for pattern in patterns {
+            for line in expand(pattern, kind) {
+                builder
+                    .add_line(None, &line)
+                    .map_err(|error| Error::InvalidPattern(format!("{pattern}: {error}")))?;
+            }
+        }
+        let matcher = builder
+            .build()
+            .map_err(|error| Error::InvalidPattern(error.to_string()))?;
+        Ok(Patterns { matcher })
+    }
+
+    fn matches(&self, path: &Path, is_dir: bool) -> bool {
+        matches!(self.matcher.matched(path, is_dir), Match::Ignore(_))
+    }
+}
+
+fn is_bare(pattern: &str) -> bool {
+    !pattern.contains(['*', '?', '[', '/', '\\'])
+}
+
+fn expand(pattern: &str, kind: PatternKind) -> Vec<String> {
+    let (negation, word) = match pattern.strip_prefix('!') {
+        Some(word) => ("!", word),
+        None => ("", pattern),
+    };
+    if !is_bare(word) {
+        return vec![pattern.to_string()];
+    }
+    let lines = match kind {
+        PatternKind::Name => vec![word.to_string(), format!("*.{word}")],
+        PatternKind::FolderName => vec![format!("{word}/")],
+        PatternKind::FileName => vec![word.to_string(), format!("{word}.*")],
+        PatternKind::Extension => vec![format!("*.{word}")],
+    };
+    lines.into_iter().map(|line| format!("{negation}{line}")).collect()
+}
+
+// Every include/exclude source of a run.
+// Precedence: `--include` > `--exclude` and the config blacklists > ignore files.
+pub struct Filters {
+    root: PathBuf,
+    include: Patterns,
+    // `--include` patterns containing a `/`, split in folders, so excluded folders they point into are still walked.
+    // `None` stands for `**`.
+    include_paths: Vec<Vec<Option<GlobMatcher>>>,
+    exclude: Patterns,
+    folder_names: Patterns,
+    file_names: Patterns,
+    file_extensions: Patterns,
+}
+
+impl Filters {
+    pub fn new(
+        root: &Path,
+        config: &Config,
+        exclude: &[String],
+        include: &[String],
+    ) -> Result<Self, Error> {
+        Ok(Filters {
+            root: root.to_owned(),
+            include: Patterns::new(root, include, PatternKind::Name)?,
+            include_paths: include_paths(include)?,
+            exclude: Patterns::new(root, exclude, PatternKind::Name)?,
+            folder_names: Patterns::new(
+                root,
+                &config.blacklisted_folder_names,
+                PatternKind::FolderName,
+            )?,
+            file_names: Patterns::new(root, &config.blacklisted_file_names, PatternKind::FileName)?,
+            file_extensions: Patterns::new(
+                root,
+                &config.blacklisted_file_extensions,
+                PatternKind::Extension,
+            )?,
+        })
+    }
+
+    // Only the command line patterns, for archives that weren't made from the config blacklists
+    pub fn from_args(root: &Path, exclude: &[String], include: &[String]) -> Result<Self, Error> {
+        let none = Vec::new();
+        Ok(Filters {
+            root: root.to_owned(),
+            include: Patterns::new(root, include, PatternKind::Name)?,
+            include_paths: include_paths(include)?,
+            exclude: Patterns::new(root, exclude, PatternKind::Name)?,
+            folder_names: Patterns::new(root, &none, PatternKind::FolderName)?,
+            file_names: Patterns::new(root, &none, PatternKind::FileName)?,
+            file_extensions: Patterns::new(root, &none, PatternKind::Extension)?,
+        })
+    }
+
+    // Matched by `--include`, which overrides every exclusion
+    pub fn is_included(&self, path: &Path, is_dir: bool) -> bool {
+        self.include.matches(path, is_dir)
+    }
+
+    // Whether an `--include` pattern with a `/` could match something inside the excluded folder `dir`,
+    // such as `build/keep.txt` for `build`. Patterns without one only apply to folders that are walked.
+    pub fn may_include_below(&self, dir: &Path) -> bool {
+        let Ok(relative) = dir.strip_prefix(&self.root) else { return false };
+        let folders: Vec<_> = relative.iter().collect();
+        self.include_paths.iter().any(|segments| {
+            for (index, segment) in segments.iter().enumerate() {
+                let Some(glob) = segment else { return true };
+                match folders.get(index) {
+                    Some(folder) if glob.is_match(folder) => {}
+                    Some(_) => return false,
+                    // Every folder matched and the pattern goes deeper
+                    None => return true,
+                }
+            }
+            false
+        })
+    }
+
+    pub fn is_excluded(&self, path: &Path, is_dir: bool) -> bool {
+        self.exclusion(path, is_dir).is_some()
+    }
+
+    // Which of the patterns leaves `path` out, ignore files aren't checked here
+    pub fn exclusion(&self, path: &Path, is_dir: bool) -> Option<Exclusion> {
+        if self.exclude.matches(path, is_dir) {
+            Some(Exclusion::ExcludePattern)
+        } else if is_dir {
+            self.folder_names.matches(path, true).then_some(Exclusion::BlacklistedFolderName)
+        } else if self.file_names.matches(path, false) {
+            Some(Exclusion::BlacklistedFileName)
+        } else {
+            self.file_extensions.matches(path, false).then_some(Exclusion::BlacklistedFileExtension)
+        }
+    }
+}
+
+fn include_paths(include: &[String]) -> Result<Vec<Vec<Option<GlobMatcher>>>, Error> {
+    let mut paths = Vec::new();
+    for pattern in include {
+        let path = pattern.trim_start_matches('/').trim_end_matches('/');
+        if pattern.starts_with('!') || !path.contains('/') {
+            continue;
+        }
+        let segments = path
+            .split('/')
+            .map(|segment| match segment {
+                "**" => Ok(None),
+                segment => GlobBuilder::new(segment)
+                    .case_insensitive(true)
+                    .build()
+                    .map(|glob| Some(glob.compile_matcher()))
+                    .map_err(|error| Error::InvalidPattern(format!("{pattern}: {error}"))),
+            })
+            .collect::<Result<_, _>>()?;
+        paths.push(segments);
+    }
+    Ok(paths)
+}
+
+// Why the walk left an entry out
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Exclusion {
+    ExcludePattern,
+    BlacklistedFolderName,
+    BlacklistedFileName,
+    BlacklistedFileExtension,
+    IgnoreFile,
+    SkippedSymlink,
+    BrokenSymlink,
+}
+
+impl Exclusion {
+    // Same as the serialized name, for CSV
+    pub fn code(&self) -> &'static str {
+        match self {
+            Exclusion::ExcludePattern => "exclude_pattern",
+            Exclusion::BlacklistedFolderName => "blacklisted_folder_name",
+            Exclusion::BlacklistedFileName => "blacklisted_file_name",
+            Exclusion::BlacklistedFileExtension => "blacklisted_file_extension",
+            Exclusion::IgnoreFile => "ignore_file",
+            Exclusion::SkippedSymlink => "skipped_symlink",
+            Exclusion::BrokenSymlink => "broken_symlink",
+        }
+    }
+}
+
+impl Display for Exclusion {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let text = match self {
+            Exclusion::ExcludePattern => "matches --exclude",
+            Exclusion::BlacklistedFolderName => "blacklisted folder name",
+            Exclusion::BlacklistedFileName => "blacklisted file name",
+            Exclusion::BlacklistedFileExtension => "blacklisted file extension",
+            Exclusion::IgnoreFile => "ignored by an ignore file",
+            Exclusion::SkippedSymlink => "symbolic link (--symlinks skip)",
+            Exclusion::BrokenSymlink => "broken symbolic link",
+        };
+        write!(f, "{text}")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn includes_reach_into_excluded_folders() {
+        let include = ["build/keep.txt".to_string(), "/docs/**/*.md".to_string(), "*.log".to_string()];
+        let filters = Filters::from_args(Path::new(""), &[], &include).unwrap();
+        assert!(filters.may_include_below(Path::new("build")));
+        assert!(!filters.may_include_below(Path::new("build/sub")));
+        assert!(filters.may_include_below(Path::new("docs/api/v1")));
+        assert!(!filters.may_include_below(Path::new("target")));
+        assert!(filters.is_included(Path::new("build/keep.txt"), false));
+    }
+}
@@ -1,78 +1,798 @@
-use std::fs::File;
-use serde::{Deserialize};
+// Release Manager
+
+use crate::config::Config;
 use crate::error::Error;
-use std::io::copy;
+use crate::manifest::sha256_hex;
 use crate::print_pretty_header;
+use crate::source::ReleaseSource;
+use crate::versions::Versions;
+use clap::ValueEnum;
+use inline_colorization::*;
+use minisign_verify::{PublicKey, Signature};
+use reqwest::blocking::Client;
+use semver::Version;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::{Cursor, Read, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+// The executable's name inside `.tar.gz` and `.zip` assets
+const BINARY_NAME: &str = if cfg!(windows) { "mia.exe" } else { "mia" };
+// Release assets are signed with this key (`minisign -S -m <asset>`), updates signed with any other are refused.
+// Until the maintainers' key is committed the file only holds its comment line, and updates are refused.
+const PUBLIC_KEY: &str = include_str!("minisign.pub");
+// Assets published next to the binaries that are never installed
+const SIDECAR_EXTENSIONS: [&str; 6] = ["sha256", "sha256sum", "sig", "minisig", "asc", "txt"];
+
+// Which releases `mia update` and `mia releases` consider
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum Channel {
+    #[default]
+    Stable,
+    /// Stable releases and prereleases (`2.0.0-beta.1`)
+    Prerelease,
+}
 
-const OWNER: &str = "Azuyamat";
-const REPO: &str = "mia_rust";
-const ASSET_NAME: &str = "mia.exe";
+impl FromStr for Channel {
+    type Err = Error;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        <Channel as ValueEnum>::from_str(value, true).map_err(|_| {
+            Error::ConfigActionError(format!("Unknown channel `{value}` (Expected stable or prerelease)"))
+        })
+    }
+}
 
 #[derive(Debug, Deserialize)]
-struct Asset {
-    name: String,
-    browser_download_url: String,
-    download_count: u128
+pub struct Asset {
+    pub name: String,
+    // May be relative in a release index
+    #[serde(default)]
+    pub browser_download_url: String,
+    #[serde(default)]
+    pub download_count: u128,
 }
 
 #[derive(Debug, Deserialize)]
-struct Release {
-    tag_name: String,
-    assets: Vec<Asset>,
-    name: String,
-    body: String
+pub struct Release {
+    pub tag_name: String,
+    pub assets: Vec<Asset>,
+    pub name: Option<String>,
+    pub body: Option<String>,
+    #[serde(default)]
+    pub prerelease: bool,
+    #[serde(default)]
+    draft: bool,
+    // RFC 3339
+    pub published_at: Option<String>,
 }
 
-fn get_release_by_version(version: &str) -> Result<Release, Error> {
-    let url = format!("https://api.github.com/repos/{OWNER}/{REPO}/releases/tags/{version}");
+impl Release {
+    // The tag as a semver version, without its `v` prefix
+    pub fn version(&self) -> Option<Version> {
+        parse_version(&self.tag_name)
+    }
 
-    let response = reqwest::blocking::Client::new()
-        .get(url)
-        .header(reqwest::header::USER_AGENT, "mia_cli")
-        .send()?
-        .text()?;
-    let release: Release = serde_json::from_str(&response)?;
+    // Drafts are never listed, prereleases only on their channel
+    pub fn is_listed(&self, channel: Channel) -> bool {
+        !self.draft && (channel == Channel::Prerelease || !self.prerelease)
+    }
+}
+
+pub fn parse_version(tag: &str) -> Option<Version> {
+    Version::parse(tag.trim().trim_start_matches('v')).ok()
+}
 
-    Ok(release)
+// Result of `mia update --check`
+pub struct Check {
+    pub current: Version,
+    pub latest: Release,
+}
+
+impl Check {
+    pub fn is_outdated(&self) -> bool {
+        self.latest.version().is_some_and(|latest| latest > self.current)
+    }
 }
 
-pub fn get_latest_release() -> Result<String, Error> {
-    let url = format!("https://api.github.com/repos/{OWNER}/{REPO}/releases/latest");
+// Target triple of the running build, as used in asset names (`x86_64-unknown-linux-gnu`)
+pub fn target_triple() -> String {
+    let arch = std::env::consts::ARCH;
+    let rest = if cfg!(target_os = "macos") {
+        "apple-darwin"
+    } else if cfg!(all(windows, target_env = "gnu")) {
+        "pc-windows-gnu"
+    } else if cfg!(windows) {
+        "pc-windows-msvc"
+    } else if cfg!(all(target_os = "linux", target_env = "musl")) {
+        "unknown-linux-musl"
+    } else if cfg!(target_os = "linux") {
+        "unknown-linux-gnu"
+    } else {
+        std::env::consts::OS
+    };
+    format!("{arch}-{rest}")
+}
+
+// Other spellings of a triple's OS and arch found in asset names (`linux-amd64`, `macos-arm64`)
+fn aliases(triple: &str) -> (&'static [&'static str], &'static [&'static str]) {
+    let os: &[&str] = if triple.contains("darwin") {
+        &["darwin", "macos", "apple"]
+    } else if triple.contains("windows") {
+        &["windows", "win64"]
+    } else if triple.contains("linux") {
+        &["linux"]
+    } else {
+        &[]
+    };
+    let arch: &[&str] = match triple.split('-').next().unwrap_or_default() {
+        "x86_64" => &["x86_64", "amd64", "x64"],
+        "aarch64" => &["aarch64", "arm64"],
+        "x86" | "i686" => &["i686", "386"],
+        _ => &[],
+    };
+    (os, arch)
+}
+
+// The asset built for `triple`: one naming the full triple, then one naming its OS and arch,
+// then the `mia.exe` older Windows releases shipped
+fn select_asset<'a>(assets: &'a [Asset], triple: &str) -> Option<&'a Asset> {
+    let candidates: Vec<&Asset> = assets
+        .iter()
+        .filter(|asset| {
+            let extension = asset.name.rsplit_once('.').map(|(_, extension)| extension.to_ascii_lowercase());
+            !extension.is_some_and(|extension| SIDECAR_EXTENSIONS.contains(&extension.as_str()))
+        })
+        .collect();
+    let (os, arch) = aliases(triple);
+    candidates
+        .iter()
+        .find(|asset| asset.name.contains(triple))
+        .or_else(|| {
+            candidates.iter().find(|asset| {
+                let name = asset.name.to_ascii_lowercase();
+                os.iter().any(|os| name.contains(os)) && arch.iter().any(|arch| name.contains(arch))
+            })
+        })
+        .or_else(|| {
+            candidates
+                .iter()
+                .find(|asset| triple.contains("windows") && asset.name == "mia.exe")
+        })
+        .copied()
+}
+
+pub struct Updater {
+    source: Box<dyn ReleaseSource>,
+    target: String,
+    // The file being replaced, with symlinks resolved
+    executable: PathBuf,
+    // `None` when this build has no release key
+    public_key: Option<PublicKey>,
+    // Of the running executable, the one retained before it's replaced
+    version: String,
+    versions: Versions,
+    channel: Channel,
+}
+
+impl Updater {
+    pub fn new(config: &Config) -> Result<Self, Error> {
+        Ok(Updater {
+            source: crate::source::open(config.release_source.as_deref(), Client::new())?,
+            target: target_triple(),
+            executable: std::env::current_exe()?.canonicalize()?,
+            public_key: embedded_key(PUBLIC_KEY)?,
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            versions: Versions::new(config.keep_versions)?,
+            channel: config.channel,
+        })
+    }
+
+    // The given tag, or the newest release of the channel
+    fn get_release(&self, version: Option<&str>) -> Result<Release, Error> {
+        match version {
+            Some(version) => self.source.release(version),
+            None => self.source.latest(self.channel),
+        }
+    }
+
+    // Up to `limit` releases of the channel, newest first
+    pub fn releases(&self, limit: usize) -> Result<Vec<Release>, Error> {
+        self.source.releases(self.channel, limit)
+    }
+
+    // The running version against the newest release of the channel, by semver ordering
+    pub fn check(&self) -> Result<Check, Error> {
+        let current = parse_version(&self.version)
+            .ok_or_else(|| Error::Custom(format!("Invalid current version {}", self.version)))?;
+        Ok(Check { current, latest: self.get_release(None)? })
+    }
+
+    pub fn version(&self) -> &str {
+        &self.version
+    }
+
+    // Replaces the running executable with the release's asset for this platform, returns the installed tag
+    pub fn update(&self, version: Option<&str>) -> Result<String, Error> {
+        // Checked first, nothing is downloaded when the result couldn't be verified
+        let public_key = self.public_key.as_ref().ok_or_else(|| {
+            Error::from("No release key configured, this build of mia can't verify updates. Download new versions from the releases page instead.")
+        })?;
+        let release = self.get_release(version)?;
+        let asset = select_asset(&release.assets, &self.target).ok_or_else(|| {
+            Error::Custom(format!("Release {} has no asset for {}", release.tag_name, self.target))
+        })?;
+
+        println!("Updating Mia to {color_bright_green}{}{color_reset}", release.tag_name);
+        if let Some(name) = &release.name {
+            println!("{name}");
+        }
+        println!("{} downloads", asset.download_count);
+        match release.body.as_deref().map(str::trim) {
+            Some(notes) if !notes.is_empty() => {
+                print_pretty_header("Release notes", 4);
+                println!("{notes}");
+            }
+            _ => println!("No notes"),
+        }
+
+        // A release without them can't be checked, so it isn't installed
+        let checksums = find_asset(&release, &[format!("{}.sha256", asset.name), "SHA256SUMS".to_string()])
+            .ok_or_else(|| Error::Custom(format!("Release {} has no checksum for {}", release.tag_name, asset.name)))?;
+        let signature = find_asset(&release, &[format!("{}.minisig", asset.name)])
+            .ok_or_else(|| Error::Custom(format!("Release {} has no signature for {}", release.tag_name, asset.name)))?;
 
-    let response = reqwest::blocking::Client::new()
-        .get(url)
-        .header(reqwest::header::USER_AGENT, "mia_cli")
-        .send()?
-        .text()?;
-    let release: Release = serde_json::from_str(&response)?;
+        println!("Downloading {color_cyan}{}{color_reset}...", asset.name);
+        let bytes = self.source.download(asset)?;
+        let checksums = String::from_utf8_lossy(&self.source.download(checksums)?).to_string();
+        let signature = String::from_utf8_lossy(&self.source.download(signature)?).to_string();
+        verify(&asset.name, &bytes, &checksums, &signature, public_key)?;
+        println!("Verified the SHA-256 and signature of {color_cyan}{}{color_reset}", asset.name);
 
-    Ok(release.tag_name)
+        let binary = unpack(&asset.name, &bytes)?;
+        self.versions.retain(&self.executable, &self.version)?;
+        replace_executable(&self.executable, &binary)?;
+        Ok(release.tag_name)
+    }
+
+    // Puts the newest retained binary back in place of the running one, returns its version
+    pub fn rollback(&self) -> Result<String, Error> {
+        let Some(previous) = self.versions.list()?.into_iter().next() else {
+            return Err(Error::Custom(format!(
+                "No previous version to roll back to in {}",
+                self.versions.dir().display()
+            )));
+        };
+        replace_executable(&self.executable, &fs::read(&previous.path)?)?;
+        // Installed again, so the next rollback goes one version further back
+        fs::remove_file(&previous.path)?;
+        Ok(previous.version)
+    }
+
+    pub fn versions(&self) -> &Versions {
+        &self.versions
+    }
 }
 
-pub fn get_download_link_for_asset(version: &str) -> Result<String, Error> {
-    let release = get_release_by_version(version)?;
+// The key line is missing until the maintainers' key is committed
+fn embedded_key(text: &str) -> Result<Option<PublicKey>, Error> {
+    if text.lines().nth(1).is_none_or(|line| line.trim().is_empty()) {
+        return Ok(None);
+    }
+    PublicKey::decode(text)
+        .map(Some)
+        .map_err(|error| Error::Custom(format!("Invalid embedded public key: {error}")))
+}
 
-    let download_count = release.assets[0].download_count;
+// The first asset of `release` with one of `names`
+fn find_asset<'a>(release: &'a Release, names: &[String]) -> Option<&'a Asset> {
+    names
+        .iter()
+        .find_map(|name| release.assets.iter().find(|asset| asset.name == *name))
+}
+
+// The hash listed for `name` in `<hash>  <name>` lines (a `SHA256SUMS`), or a lone hash (`<asset>.sha256`)
+fn expected_sha256(checksums: &str, name: &str) -> Option<String> {
+    checksums.lines().find_map(|line| {
+        let mut parts = line.split_whitespace();
+        let hash = parts.next()?;
+        match parts.next() {
+            Some(file) if Path::new(file.trim_start_matches('*')).file_name() != Some(name.as_ref()) => None,
+            _ => Some(hash.to_ascii_lowercase()),
+        }
+    })
+}
 
-    println!("{}", release.name);
-    println!("{download_count} downloads");
-    let notes = release.body.trim();
-    if notes.is_empty() {
-        println!("No notes");
+// Checks the downloaded asset against its published SHA-256 and its minisign signature
+fn verify(name: &str, bytes: &[u8], checksums: &str, signature: &str, public_key: &PublicKey) -> Result<(), Error> {
+    let expected = expected_sha256(checksums, name)
+        .ok_or_else(|| Error::Custom(format!("The checksum file doesn't list {name}")))?;
+    let actual = sha256_hex(bytes);
+    if expected != actual {
+        return Err(Error::Custom(format!("SHA-256 of {name} is {actual}, expected {expected}")));
+    }
+    let signature = Signature::decode(signature)
+        .map_err(|error| Error::Custom(format!("Invalid signature for {name}: {error}")))?;
+    // Only prehashed signatures, the default since minisign 0.8
+    public_key
+        .verify(bytes, &signature, false)
+        .map_err(|error| Error::Custom(format!("Signature check of {name} failed: {error}")))
+}
+
+// The executable itself, taken out of `.tar.gz` and `.zip` assets
+fn unpack(name: &str, bytes: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut binary = Vec::new();
+    if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+        let mut archive = tar::Archive::new(flate2::read::GzDecoder::new(bytes));
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            if entry.path()?.file_name().is_some_and(|file_name| file_name == BINARY_NAME) {
+                entry.read_to_end(&mut binary)?;
+                break;
+            }
+        }
+    } else if name.ends_with(".zip") {
+        let mut archive = zip::ZipArchive::new(Cursor::new(bytes))?;
+        for index in 0..archive.len() {
+            let mut entry = archive.by_index(index)?;
+            if entry.is_file() && Path::new(entry.name()).file_name().is_some_and(|file_name| file_name == BINARY_NAME) {
+                entry.read_to_end(&mut binary)?;
+                break;
+            }
+        }
     } else {
-        print_pretty_header("Release notes", 4);
-        println!("{}", release.body);
+        binary.extend_from_slice(bytes);
+    }
+    if binary.is_empty() {
+        return Err(Error::Custom(format!("{name} doesn't contain {BINARY_NAME}")));
     }
+    Ok(binary)
+}
+
+// Writes the new binary next to `executable` with its permissions, then swaps it in. On Unix the
+// rename is atomic, on Windows the running executable is moved aside first as it can't be replaced.
+fn replace_executable(executable: &Path, binary: &[u8]) -> Result<(), Error> {
+    let dir = executable.parent().ok_or(Error::PathNotFound)?;
+    let file_name = executable.file_name().ok_or(Error::PathNotFound)?.to_string_lossy();
+    let staged = dir.join(format!(".{file_name}.new"));
 
-    if let Some(asset) = release.assets.iter().find(|a| a.name == ASSET_NAME) {
-        return Ok(asset.browser_download_url.clone());
+    let result = stage(&staged, executable, binary).and_then(|_| swap(&staged, executable));
+    if result.is_err() {
+        let _ = fs::remove_file(&staged);
     }
+    result.map_err(|error| match error.kind() {
+        std::io::ErrorKind::PermissionDenied => Error::Custom(format!(
+            "Can't replace {}, run the update as a user allowed to write to {}",
+            executable.display(),
+            dir.display()
+        )),
+        _ => Error::IO(error),
+    })
+}
+
+fn stage(staged: &Path, executable: &Path, binary: &[u8]) -> std::io::Result<()> {
+    let mut file = fs::File::create(staged)?;
+    file.write_all(binary)?;
+    file.set_permissions(fs::metadata(executable)?.permissions())?;
+    file.sync_all()
+}
+
+#[cfg(unix)]
+fn swap(staged: &Path, executable: &Path) -> std::io::Result<()> {
+    fs::rename(staged, executable)
+}
 
-    Err("Asset not found or download URL not available".into())
+#[cfg(not(unix))]
+fn swap(staged: &Path, executable: &Path) -> std::io::Result<()> {
+    let old = executable.with_extension("old.exe");
+    // Left behind by the previous update
+    let _ = fs::remove_file(&old);
+    fs::rename(executable, &old)?;
+    fs::rename(staged, executable).inspect_err(|_| {
+        let _ = fs::rename(&old, executable);
+    })
 }
 
-pub fn download_asset(url: &str, destination: &mut File) -> Result<(), Error> {
-    let response = reqwest::blocking::get(url)?;
-    copy(&mut response.bytes().unwrap().as_ref(), destination)?;
-    Ok(())
-}
\ No newline at end of file
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::source::GitHub;
+    use base64::engine::general_purpose::STANDARD as BASE64;
+    use base64::Engine;
+    use blake2::{Blake2b512, Digest};
+    use ed25519_dalek::{Signer, SigningKey};
+    use std::collections::HashMap;
+    use std::thread;
+
+    // A tag and its assets' names and contents
+    type MockRelease<'a> = (&'a str, Vec<(String, Vec<u8>)>);
+
+    const OWNER: &str = "team";
+    const REPO: &str = "mia";
+    const KEY_ID: [u8; 8] = [1, 2, 3, 4, 5, 6, 7, 8];
+    const MOCK_PAGE_SIZE: usize = 2;
+
+    fn signing_key() -> SigningKey {
+        SigningKey::from_bytes(&[7; 32])
+    }
+
+    fn public_key() -> PublicKey {
+        let mut key = b"Ed".to_vec();
+        key.extend_from_slice(&KEY_ID);
+        key.extend_from_slice(signing_key().verifying_key().as_bytes());
+        PublicKey::from_base64(&BASE64.encode(key)).unwrap()
+    }
+
+    // A prehashed minisign signature, as made by `minisign -S`
+    fn sign(key: &SigningKey, content: &[u8]) -> Vec<u8> {
+        let signature = key.sign(&Blake2b512::digest(content)).to_bytes();
+        let trusted_comment = "timestamp:0\tfile:mia";
+        let mut global = signature.to_vec();
+        global.extend_from_slice(trusted_comment.as_bytes());
+        let mut line = b"ED".to_vec();
+        line.extend_from_slice(&KEY_ID);
+        line.extend_from_slice(&signature);
+        format!(
+            "untrusted comment: signature from minisign secret key\n{}\ntrusted comment: {trusted_comment}\n{}\n",
+            BASE64.encode(line),
+            BASE64.encode(key.sign(&global).to_bytes())
+        )
+        .into_bytes()
+    }
+
+    // An asset with its checksum and signature
+    fn published(name: &str, content: &[u8]) -> Vec<(String, Vec<u8>)> {
+        vec![
+            (name.to_string(), content.to_vec()),
+            (format!("{name}.sha256"), format!("{}  {name}\n", sha256_hex(content)).into_bytes()),
+            (format!("{name}.minisig"), sign(&signing_key(), content)),
+        ]
+    }
+
+    // Serves `releases` (oldest first) like the GitHub API, in pages of `MOCK_PAGE_SIZE`, with every
+    // asset downloadable from the same server. Tags with a `-` are prereleases.
+    struct MockServer {
+        url: String,
+    }
+
+    impl MockServer {
+        fn start(releases: Vec<MockRelease>) -> MockServer {
+            let server = tiny_http::Server::http("127.0.0.1:0").unwrap();
+            let url = format!("http://{}", server.server_addr().to_ip().unwrap());
+            let mut routes: HashMap<String, Vec<u8>> = HashMap::new();
+            let mut latest = Vec::new();
+            let mut listed_releases = Vec::new();
+            for (day, (tag, assets)) in releases.into_iter().enumerate() {
+                let mut listed = Vec::new();
+                for (name, content) in assets {
+                    let name = name.as_str();
+                    let path = format!("/download/{tag}/{name}");
+                    listed.push(serde_json::json!({ "name": name, "browser_download_url": format!("{url}{path}") }));
+                    routes.insert(path, content);
+                }
+                let release = serde_json::json!({
+                    "tag_name": tag,
+                    "name": tag,
+                    "body": format!("Notes for {tag}"),
+                    "prerelease": tag.contains('-'),
+                    "published_at": format!("2024-01-{:02}T12:00:00Z", day + 1),
+                    "assets": listed,
+                });
+                let body = serde_json::to_vec(&release).unwrap();
+                routes.insert(format!("/repos/{OWNER}/{REPO}/releases/tags/{tag}"), body.clone());
+                if !tag.contains('-') {
+                    latest = body;
+                }
+                listed_releases.push(release);
+            }
+            routes.insert(format!("/repos/{OWNER}/{REPO}/releases/latest"), latest);
+            listed_releases.reverse();
+            // One page past the end, which is empty
+            for page in 1..=listed_releases.len() / MOCK_PAGE_SIZE + 1 {
+                let start = ((page - 1) * MOCK_PAGE_SIZE).min(listed_releases.len());
+                let end = (page * MOCK_PAGE_SIZE).min(listed_releases.len());
+                routes.insert(
+                    format!("/repos/{OWNER}/{REPO}/releases?per_page={MOCK_PAGE_SIZE}&page={page}"),
+                    serde_json::to_vec(&listed_releases[start..end]).unwrap(),
+                );
+            }
+
+            thread::spawn(move || {
+                for request in server.incoming_requests() {
+                    let response = match routes.get(request.url()) {
+                        Some(body) => tiny_http::Response::from_data(body.clone()),
+                        None => tiny_http::Response::from_data(b"Not Found".to_vec()).with_status_code(404),
+                    };
+                    let _ = request.respond(response);
+                }
+            });
+            MockServer { url }
+        }
+
+        fn updater(&self, target: &str, executable: &Path) -> Updater {
+            let client = Client::builder().no_proxy().build().unwrap();
+            let source = GitHub::new(format!("{}/repos/{OWNER}/{REPO}", self.url), client).with_page_size(MOCK_PAGE_SIZE);
+            updater(Box::new(source), target, executable)
+        }
+    }
+
+    // Retains binaries in a `versions` folder next to `executable`
+    fn updater(source: Box<dyn ReleaseSource>, target: &str, executable: &Path) -> Updater {
+        Updater {
+            source,
+            target: target.to_string(),
+            executable: executable.to_path_buf(),
+            public_key: Some(public_key()),
+            version: "0.9.0".to_string(),
+            versions: Versions::in_dir(executable.parent().unwrap().join("versions"), 2),
+            channel: Channel::Stable,
+        }
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("mia-release-{}-{name}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn installed(dir: &Path, content: &[u8]) -> PathBuf {
+        let executable = dir.join("mia");
+        fs::write(&executable, content).unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(&executable, fs::Permissions::from_mode(0o751)).unwrap();
+        }
+        executable
+    }
+
+    fn asset(name: &str) -> Asset {
+        Asset { name: name.to_string(), browser_download_url: String::new(), download_count: 0 }
+    }
+
+    #[test]
+    fn selects_the_asset_for_the_target() {
+        let assets = [
+            asset("mia-x86_64-unknown-linux-gnu.tar.gz.sha256"),
+            asset("mia-aarch64-apple-darwin.tar.gz"),
+            asset("mia-x86_64-unknown-linux-gnu.tar.gz"),
+            asset("mia-linux-arm64"),
+            asset("mia.exe"),
+        ];
+        let name = |triple| select_asset(&assets, triple).map(|asset| asset.name.as_str());
+        assert_eq!(name("x86_64-unknown-linux-gnu"), Some("mia-x86_64-unknown-linux-gnu.tar.gz"));
+        assert_eq!(name("aarch64-unknown-linux-gnu"), Some("mia-linux-arm64"));
+        assert_eq!(name("aarch64-apple-darwin"), Some("mia-aarch64-apple-darwin.tar.gz"));
+        assert_eq!(name("x86_64-pc-windows-msvc"), Some("mia.exe"));
+        assert_eq!(name("x86_64-apple-darwin"), None);
+        assert_eq!(select_asset(&[asset("mia-x86_64-apple-darwin")], "x86_64-pc-windows-msvc").map(|asset| &asset.name), None);
+    }
+
+    #[test]
+    fn replaces_the_executable_and_keeps_its_permissions() {
+        let server = MockServer::start(vec![
+            ("v1.0.0", published("mia-x86_64-unknown-linux-gnu", b"version 1")),
+            ("v2.0.0", [
+                published("mia-aarch64-unknown-linux-gnu", b"wrong arch"),
+                published("mia-x86_64-unknown-linux-gnu", b"version 2"),
+            ]
+            .concat()),
+        ]);
+        let dir = temp_dir("replace");
+        let executable = installed(&dir, b"installed");
+        let updater = server.updater("x86_64-unknown-linux-gnu", &executable);
+
+        assert_eq!(updater.update(None).unwrap(), "v2.0.0");
+        assert_eq!(fs::read(&executable).unwrap(), b"version 2");
+        assert_eq!(updater.update(Some("v1.0.0")).unwrap(), "v1.0.0");
+        assert_eq!(fs::read(&executable).unwrap(), b"version 1");
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(fs::metadata(&executable).unwrap().permissions().mode() & 0o777, 0o751);
+        }
+        // Nothing staged is left next to it, only the versions folder
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 2);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn takes_the_binary_out_of_archives() {
+        let mut tar_gz = Vec::new();
+        {
+            let encoder = flate2::write::GzEncoder::new(&mut tar_gz, flate2::Compression::default());
+            let mut builder = tar::Builder::new(encoder);
+            let content = b"from tar";
+            let mut header = tar::Header::new_gnu();
+            header.set_size(content.len() as u64);
+            header.set_mode(0o755);
+            builder.append_data(&mut header, "README.md", &b"readme"[..]).unwrap();
+            builder
+                .append_data(&mut header.clone(), format!("mia-v2/{BINARY_NAME}"), &content[..])
+                .unwrap();
+            builder.into_inner().unwrap().finish().unwrap();
+        }
+        let server = MockServer::start(vec![("v2.0.0", published("mia-x86_64-unknown-linux-gnu.tar.gz", &tar_gz))]);
+        let dir = temp_dir("archive");
+        let executable = installed(&dir, b"installed");
+
+        server.updater("x86_64-unknown-linux-gnu", &executable).update(None).unwrap();
+        assert_eq!(fs::read(&executable).unwrap(), b"from tar");
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn leaves_the_executable_alone_without_an_asset() {
+        let server = MockServer::start(vec![("v2.0.0", published("mia-x86_64-apple-darwin", b"mac"))]);
+        let dir = temp_dir("missing");
+        let executable = installed(&dir, b"installed");
+        let updater = server.updater("x86_64-unknown-linux-gnu", &executable);
+
+        assert!(updater.update(None).is_err());
+        assert!(updater.update(Some("v9.9.9")).is_err());
+        assert_eq!(fs::read(&executable).unwrap(), b"installed");
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn rolls_back_to_retained_versions() {
+        let server = MockServer::start(vec![
+            ("v1.0.0", published("mia-x86_64-unknown-linux-gnu", b"version 1")),
+            ("v2.0.0", published("mia-x86_64-unknown-linux-gnu", b"version 2")),
+            ("v3.0.0", published("mia-x86_64-unknown-linux-gnu", b"version 3")),
+        ]);
+        let dir = temp_dir("rollback");
+        let executable = installed(&dir, b"installed");
+        let mut updater = server.updater("x86_64-unknown-linux-gnu", &executable);
+
+        assert!(updater.rollback().is_err());
+        for version in ["1.0.0", "2.0.0", "3.0.0"] {
+            updater.update(Some(&format!("v{version}"))).unwrap();
+            // What a run of the new binary would report
+            updater.version = version.to_string();
+        }
+        // Two are kept, the originally installed 0.9.0 was dropped
+        let retained: Vec<String> = updater.versions.list().unwrap().into_iter().map(|installed| installed.version).collect();
+        assert_eq!(retained, ["2.0.0", "1.0.0"]);
+
+        assert_eq!(updater.rollback().unwrap(), "2.0.0");
+        assert_eq!(fs::read(&executable).unwrap(), b"version 2");
+        assert_eq!(updater.rollback().unwrap(), "1.0.0");
+        assert_eq!(fs::read(&executable).unwrap(), b"version 1");
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(fs::metadata(&executable).unwrap().permissions().mode() & 0o777, 0o751);
+        }
+        assert!(updater.rollback().is_err());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn pages_through_releases_of_the_channel() {
+        let server = MockServer::start(
+            ["v1.9.0", "v1.10.0", "v2.0.0-beta.1", "v1.10.1", "v2.0.0-rc.1"]
+                .into_iter()
+                .map(|tag| (tag, published("mia-x86_64-unknown-linux-gnu", tag.as_bytes())))
+                .collect(),
+        );
+        let dir = temp_dir("releases");
+        let mut updater = server.updater("x86_64-unknown-linux-gnu", &installed(&dir, b"installed"));
+        let tags = |releases: Vec<Release>| releases.into_iter().map(|release| release.tag_name).collect::<Vec<_>>();
+
+        assert_eq!(tags(updater.releases(usize::MAX).unwrap()), ["v1.10.1", "v1.10.0", "v1.9.0"]);
+        assert_eq!(tags(updater.releases(2).unwrap()), ["v1.10.1", "v1.10.0"]);
+        let release = updater.releases(1).unwrap().remove(0);
+        assert_eq!(release.published_at.as_deref(), Some("2024-01-04T12:00:00Z"));
+        assert_eq!(release.body.as_deref(), Some("Notes for v1.10.1"));
+
+        // 1.10.0 sorts after 1.9.0, which a plain string comparison gets wrong
+        updater.version = "1.9.0".to_string();
+        let check = updater.check().unwrap();
+        assert_eq!(check.latest.tag_name, "v1.10.1");
+        assert!(check.is_outdated());
+        updater.version = "1.10.1".to_string();
+        assert!(!updater.check().unwrap().is_outdated());
+        // A local build newer than any release
+        updater.version = "1.11.0".to_string();
+        assert!(!updater.check().unwrap().is_outdated());
+
+        updater.channel = Channel::Prerelease;
+        assert_eq!(tags(updater.releases(usize::MAX).unwrap()).len(), 5);
+        let check = updater.check().unwrap();
+        assert_eq!(check.latest.tag_name, "v2.0.0-rc.1");
+        assert!(check.is_outdated());
+        // Prereleases sort before their release
+        updater.version = "2.0.0".to_string();
+        assert!(!updater.check().unwrap().is_outdated());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn updates_from_a_mirror_folder() {
+        let dir = temp_dir("mirror");
+        let mirror = dir.join("mirror");
+        fs::create_dir_all(&mirror).unwrap();
+        let mut assets = Vec::new();
+        for (name, content) in published("mia-x86_64-unknown-linux-gnu", b"mirrored") {
+            fs::write(mirror.join(&name), content).unwrap();
+            // Relative to the index
+            assets.push(serde_json::json!({ "name": name }));
+        }
+        let index = serde_json::json!([{ "tag_name": "v2.0.0", "assets": assets }]);
+        fs::write(mirror.join("releases.json"), serde_json::to_vec(&index).unwrap()).unwrap();
+        let executable = installed(&dir, b"installed");
+
+        let url = reqwest::Url::from_directory_path(&mirror).unwrap();
+        let source = crate::source::open(Some(url.as_str()), Client::new()).unwrap();
+        let updater = updater(source, "x86_64-unknown-linux-gnu", &executable);
+        let check = updater.check().unwrap();
+        assert_eq!(check.latest.tag_name, "v2.0.0");
+        assert!(check.is_outdated());
+        assert_eq!(updater.update(None).unwrap(), "v2.0.0");
+        assert_eq!(fs::read(&executable).unwrap(), b"mirrored");
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    // Every asset of the release is served, with `replace` swapping some of them out
+    fn refused(name: &str, replace: Vec<(String, Vec<u8>)>, reason: &str) {
+        let mut assets = published("mia-x86_64-unknown-linux-gnu", b"version 2");
+        assets.retain(|(asset, _)| !replace.iter().any(|(replaced, _)| replaced == asset));
+        assets.extend(replace.into_iter().filter(|(_, content)| !content.is_empty()));
+        let server = MockServer::start(vec![("v2.0.0", assets)]);
+        let dir = temp_dir(name);
+        let executable = installed(&dir, b"installed");
+
+        let error = server.updater("x86_64-unknown-linux-gnu", &executable).update(None).unwrap_err();
+        assert!(format!("{error:?}").contains(reason), "{error:?}");
+        assert_eq!(fs::read(&executable).unwrap(), b"installed");
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn refuses_updates_that_fail_verification() {
+        let name = "mia-x86_64-unknown-linux-gnu";
+        // Altered after the checksum and signature were published
+        refused("altered", vec![(name.to_string(), b"version 2, altered".to_vec())], "SHA-256 of");
+        // Checksum updated as well, but the signature no longer matches
+        refused("resigned", vec![
+            (name.to_string(), b"evil".to_vec()),
+            (format!("{name}.sha256"), format!("{}  {name}\n", sha256_hex(b"evil")).into_bytes()),
+        ], "Signature check");
+        // Signed with another key
+        refused("other-key", vec![(format!("{name}.minisig"), sign(&SigningKey::from_bytes(&[9; 32]), b"version 2"))], "Signature check");
+        // Missing checksum or signature
+        refused("no-checksum", vec![(format!("{name}.sha256"), Vec::new())], "no checksum");
+        refused("no-signature", vec![(format!("{name}.minisig"), Vec::new())], "no signature");
+    }
+
+    #[test]
+    fn reads_checksum_files() {
+        let sums = "AAAA  mia-x86_64-apple-darwin\nbbbb *dist/mia-x86_64-unknown-linux-gnu\n";
+        assert_eq!(expected_sha256(sums, "mia-x86_64-unknown-linux-gnu").as_deref(), Some("bbbb"));
+        assert_eq!(expected_sha256(sums, "mia-x86_64-apple-darwin").as_deref(), Some("aaaa"));
+        assert_eq!(expected_sha256(sums, "mia.exe"), None);
+        assert_eq!(expected_sha256("cccc\n", "mia").as_deref(), Some("cccc"));
+    }
+
+    #[test]
+    fn refuses_updates_without_a_release_key() {
+        assert!(embedded_key(PUBLIC_KEY).is_ok());
+        assert!(embedded_key("untrusted comment: no release key\n").unwrap().is_none());
+        let text = format!("untrusted comment: test key\n{}\n", BASE64.encode(b"not a key"));
+        assert!(embedded_key(&text).is_err());
+
+        let server = MockServer::start(vec![("v1.0.0", published("mia-x86_64-unknown-linux-gnu", b"version 1"))]);
+        let dir = temp_dir("no-key");
+        let executable = installed(&dir, b"installed");
+        let mut updater = server.updater("x86_64-unknown-linux-gnu", &executable);
+        updater.public_key = None;
+        let error = updater.update(None).unwrap_err();
+        assert!(format!("{error:?}").contains("No release key configured"));
+        assert_eq!(fs::read(&executable).unwrap(), b"installed");
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
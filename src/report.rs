@@ -0,0 +1,307 @@
+// Run Report
+
+use crate::error::Error;
+use crate::languages::{Language, LineStats};
+use crate::patterns::Exclusion;
+use clap::ValueEnum;
+use inline_colorization::*;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, ValueEnum)]
+pub enum ReportFormat {
+    Json,
+    Csv,
+    Markdown,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EntryKind {
+    File,
+    Dir,
+    Symlink,
+}
+
+impl EntryKind {
+    fn code(&self) -> &'static str {
+        match self {
+            EntryKind::File => "file",
+            EntryKind::Dir => "dir",
+            EntryKind::Symlink => "symlink",
+        }
+    }
+}
+
+// A walked path, included or not
+#[derive(Serialize)]
+pub struct ReportEntry {
+    // Relative to the zipped folder
+    pub path: String,
+    pub kind: EntryKind,
+    pub included: bool,
+    // Only set for excluded entries
+    pub reason: Option<Exclusion>,
+    pub size: u64,
+    // Only set for included files
+    pub language: Option<String>,
+    pub generated: bool,
+    pub code: i128,
+    pub comments: i128,
+    pub blanks: i128,
+}
+
+impl ReportEntry {
+    pub fn included(path: &str, kind: EntryKind, size: u64) -> Self {
+        ReportEntry {
+            path: path.to_string(),
+            kind,
+            included: true,
+            reason: None,
+            size,
+            language: None,
+            generated: false,
+            code: 0,
+            comments: 0,
+            blanks: 0,
+        }
+    }
+
+    pub fn excluded(path: &str, kind: EntryKind, size: u64, reason: Exclusion) -> Self {
+        ReportEntry {
+            included: false,
+            reason: Some(reason),
+            ..ReportEntry::included(path, kind, size)
+        }
+    }
+
+    pub fn with_lines(mut self, language: &Language, lines: LineStats, generated: bool) -> Self {
+        self.language = Some(language.to_string());
+        self.generated = generated;
+        self.code = lines.code;
+        self.comments = lines.comments;
+        self.blanks = lines.blanks;
+        self
+    }
+}
+
+// Totals of the included entries, generated files included
+#[derive(Default, Serialize)]
+pub struct ReportTotals {
+    pub files: u64,
+    pub excluded: u64,
+    pub size: u64,
+    pub code: i128,
+    pub comments: i128,
+    pub blanks: i128,
+}
+
+#[derive(Serialize)]
+pub struct Report {
+    pub archive: Option<String>,
+    pub archive_size: u64,
+    pub elapsed_ms: u128,
+    pub totals: ReportTotals,
+    pub entries: Vec<ReportEntry>,
+}
+
+// Where and how the report of a run is written
+pub struct ReportOptions {
+    pub format: ReportFormat,
+    // Printed instead of the usual output when not set
+    pub file: Option<PathBuf>,
+}
+
+impl Report {
+    pub fn new() -> Self {
+        Report {
+            archive: None,
+            archive_size: 0,
+            elapsed_ms: 0,
+            totals: ReportTotals::default(),
+            entries: Vec::new(),
+        }
+    }
+
+    pub fn push(&mut self, entry: ReportEntry) {
+        if !entry.included {
+            self.totals.excluded += 1;
+        } else if entry.kind != EntryKind::Dir {
+            self.totals.files += 1;
+            self.totals.size += entry.size;
+            self.totals.code += entry.code;
+            self.totals.comments += entry.comments;
+            self.totals.blanks += entry.blanks;
+        }
+        self.entries.push(entry);
+    }
+
+    pub fn write(&self, options: &ReportOptions) -> Result<(), Error> {
+        let text = match options.format {
+            ReportFormat::Json => serde_json::to_string_pretty(self)? + "\n",
+            ReportFormat::Csv => self.to_csv(),
+            ReportFormat::Markdown => self.to_markdown(),
+        };
+        match &options.file {
+            Some(file) => write_file(file, &text),
+            None => {
+                print!("{text}");
+                Ok(())
+            }
+        }
+    }
+
+    // Entries sorted by path and indented by depth, folders with the size of what they'd hold
+    pub fn print_tree(&self) {
+        let mut folder_sizes: HashMap<&Path, u64> = HashMap::new();
+        for entry in self.entries.iter().filter(|entry| entry.included && entry.kind != EntryKind::Dir) {
+            for folder in Path::new(&entry.path).ancestors().skip(1) {
+                *folder_sizes.entry(folder).or_default() += entry.size;
+            }
+        }
+        let mut entries: Vec<&ReportEntry> = self.entries.iter().collect();
+        entries.sort_by(|a, b| Path::new(&a.path).cmp(Path::new(&b.path)));
+
+        for entry in entries {
+            let path = Path::new(&entry.path);
+            let indent = "  ".repeat(path.components().count().saturating_sub(1));
+            let mut name = path
+                .file_name()
+                .map(|name| name.to_string_lossy().to_string())
+                .unwrap_or_default();
+            if entry.kind == EntryKind::Dir {
+                name.push('/');
+            }
+            match entry.reason {
+                None => {
+                    let size = match entry.kind {
+                        EntryKind::Dir => folder_sizes.get(path).copied().unwrap_or(0),
+                        _ => entry.size,
+                    };
+                    println!("{indent}{color_green}+{color_reset} {color_cyan}{name}{color_reset} ({size} bytes)");
+                }
+                Some(reason) => {
+                    println!("{indent}{color_yellow}-{color_reset} {name} {color_yellow}({reason}){color_reset}");
+                }
+            }
+        }
+    }
+
+    // One row per entry, then a `total` row (with the included and excluded counts) and an `archive` row
+    fn to_csv(&self) -> String {
+        let mut csv = String::from("path,kind,included,reason,size,language,generated,code,comments,blanks,files,excluded,elapsed_ms\n");
+        for entry in &self.entries {
+            csv.push_str(&csv_row(&[
+                &entry.path,
+                entry.kind.code(),
+                &entry.included.to_string(),
+                entry.reason.map(|reason| reason.code()).unwrap_or_default(),
+                &entry.size.to_string(),
+                entry.language.as_deref().unwrap_or_default(),
+                &entry.generated.to_string(),
+                &entry.code.to_string(),
+                &entry.comments.to_string(),
+                &entry.blanks.to_string(),
+                "",
+                "",
+                "",
+            ]));
+        }
+        let totals = &self.totals;
+        csv.push_str(&csv_row(&[
+            "",
+            "total",
+            "",
+            "",
+            &totals.size.to_string(),
+            "",
+            "",
+            &totals.code.to_string(),
+            &totals.comments.to_string(),
+            &totals.blanks.to_string(),
+            &totals.files.to_string(),
+            &totals.excluded.to_string(),
+            "",
+        ]));
+        csv.push_str(&csv_row(&[
+            self.archive.as_deref().unwrap_or_default(),
+            "archive",
+            "",
+            "",
+            &self.archive_size.to_string(),
+            "",
+            "",
+            "",
+            "",
+            "",
+            "",
+            "",
+            &self.elapsed_ms.to_string(),
+        ]));
+        csv
+    }
+
+    fn to_markdown(&self) -> String {
+        let totals = &self.totals;
+        let mut markdown = String::from("# Mia report\n\n");
+        markdown.push_str(&format!(
+            "- Archive: `{}` ({} bytes)\n- Elapsed: {} ms\n- Files: {} ({} bytes), {} excluded\n\
+            - Lines: {} code, {} comments, {} blanks\n\n",
+            self.archive.as_deref().unwrap_or("none"),
+            self.archive_size,
+            self.elapsed_ms,
+            totals.files,
+            totals.size,
+            totals.excluded,
+            totals.code,
+            totals.comments,
+            totals.blanks
+        ));
+        markdown.push_str("| Path | Kind | Included | Reason | Size | Language | Code | Comments | Blanks |\n");
+        markdown.push_str("|---|---|---|---|---:|---|---:|---:|---:|\n");
+        for entry in &self.entries {
+            let language = match (&entry.language, entry.generated) {
+                (Some(language), true) => format!("{language} (generated)"),
+                (Some(language), false) => language.clone(),
+                (None, _) => String::new(),
+            };
+            markdown.push_str(&format!(
+                "| `{}` | {} | {} | {} | {} | {} | {} | {} | {} |\n",
+                entry.path.replace('|', "\\|"),
+                entry.kind.code(),
+                if entry.included { "yes" } else { "no" },
+                entry.reason.map(|reason| reason.to_string()).unwrap_or_default(),
+                entry.size,
+                language,
+                entry.code,
+                entry.comments,
+                entry.blanks
+            ));
+        }
+        markdown
+    }
+}
+
+fn csv_row(fields: &[&str]) -> String {
+    let fields: Vec<String> = fields
+        .iter()
+        .map(|field| {
+            if field.contains([',', '"', '\n', '\r']) {
+                format!("\"{}\"", field.replace('"', "\"\""))
+            } else {
+                field.to_string()
+            }
+        })
+        .collect();
+    fields.join(",") + "\n"
+}
+
+fn write_file(file: &Path, text: &str) -> Result<(), Error> {
+    if let Some(parent) = file.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(file, text)?;
+    Ok(())
+}
@@ -0,0 +1,317 @@
+// Release Source Manager
+
+use crate::error::Error;
+use crate::release::{parse_version, Asset, Channel, Release};
+use reqwest::blocking::Client;
+use reqwest::Url;
+use serde::de::DeserializeOwned;
+use std::fs;
+use std::path::PathBuf;
+
+// Where releases come from unless `release_source` is set
+pub const DEFAULT_SOURCE: &str = "github:Azuyamat/mia_rust";
+// Read from a folder given as a release source
+const INDEX_FILE: &str = "releases.json";
+// Releases asked for per request when paging through the releases API (GitHub allows up to 100)
+const PAGE_SIZE: usize = 100;
+
+// Lists releases and downloads their assets, `Updater` only talks to one of these
+pub trait ReleaseSource {
+    // Up to `limit` releases of the channel, newest first, drafts left out
+    fn releases(&self, channel: Channel, limit: usize) -> Result<Vec<Release>, Error>;
+
+    fn release(&self, tag: &str) -> Result<Release, Error>;
+
+    fn download(&self, asset: &Asset) -> Result<Vec<u8>, Error>;
+
+    // The newest release of the channel by semver ordering
+    fn latest(&self, channel: Channel) -> Result<Release, Error> {
+        newest(self.releases(channel, usize::MAX)?)
+    }
+}
+
+fn newest(releases: Vec<Release>) -> Result<Release, Error> {
+    releases
+        .into_iter()
+        .filter(|release| release.version().is_some())
+        .max_by_key(|release| release.version())
+        .ok_or_else(|| Error::Custom("No releases found".to_string()))
+}
+
+// A parsed `release_source` value
+#[derive(Debug, PartialEq)]
+pub enum SourceKind {
+    // The repository's API URL (`https://api.github.com/repos/<owner>/<repo>`)
+    GitHub(String),
+    // URL of the JSON index, `http(s)://` or `file://`
+    Index(String),
+}
+
+// `github:<owner>/<repo>`, `github:<host>/<owner>/<repo>` for GitHub Enterprise, or a folder holding a
+// `releases.json` (or the index itself): `https://mirror/mia/`, `file:///srv/mia/releases.json`
+pub fn parse(source: &str) -> Result<SourceKind, Error> {
+    let invalid = || {
+        Error::ConfigActionError(format!(
+            "Unknown release source `{source}` (Expected github:<owner>/<repo>, github:<host>/<owner>/<repo>, \
+            an http(s):// URL or a file:// URL)"
+        ))
+    };
+    if let Some(repository) = source.strip_prefix("github:") {
+        let (scheme, repository) = match repository.split_once("://") {
+            Some((scheme, repository)) => (scheme, repository),
+            None => ("https", repository),
+        };
+        let parts: Vec<&str> = repository.trim_matches('/').split('/').collect();
+        if parts.iter().any(|part| part.is_empty()) {
+            return Err(invalid());
+        }
+        return match parts[..] {
+            [owner, repo] => Ok(SourceKind::GitHub(format!("https://api.github.com/repos/{owner}/{repo}"))),
+            [host, owner, repo] => Ok(SourceKind::GitHub(format!("{scheme}://{host}/api/v3/repos/{owner}/{repo}"))),
+            _ => Err(invalid()),
+        };
+    }
+    let url = Url::parse(source).map_err(|_| invalid())?;
+    if !matches!(url.scheme(), "http" | "https" | "file") {
+        return Err(invalid());
+    }
+    if url.path().ends_with(".json") {
+        return Ok(SourceKind::Index(url.to_string()));
+    }
+    let folder = if url.path().ends_with('/') { url } else { Url::parse(&format!("{url}/")).map_err(|_| invalid())? };
+    Ok(SourceKind::Index(folder.join(INDEX_FILE).map_err(|_| invalid())?.to_string()))
+}
+
+pub fn open(source: Option<&str>, client: Client) -> Result<Box<dyn ReleaseSource>, Error> {
+    Ok(match parse(source.unwrap_or(DEFAULT_SOURCE))? {
+        SourceKind::GitHub(api_url) => Box::new(GitHub::new(api_url, client)),
+        SourceKind::Index(index) => Box::new(Index::new(&index, client)?),
+    })
+}
+
+// The releases API of github.com or a GitHub Enterprise host
+pub struct GitHub {
+    api_url: String,
+    client: Client,
+    page_size: usize,
+}
+
+impl GitHub {
+    pub fn new(api_url: String, client: Client) -> Self {
+        GitHub { api_url, client, page_size: PAGE_SIZE }
+    }
+
+    // Small pages make paging testable with a few releases
+    #[cfg(test)]
+    pub fn with_page_size(mut self, page_size: usize) -> Self {
+        self.page_size = page_size;
+        self
+    }
+
+    fn get<T: DeserializeOwned>(&self, path: &str) -> Result<T, Error> {
+        let response = self
+            .client
+            .get(format!("{}/{path}", self.api_url))
+            .header(reqwest::header::USER_AGENT, "mia_cli")
+            .send()?
+            .error_for_status()?
+            .text()?;
+        Ok(serde_json::from_str(&response)?)
+    }
+}
+
+impl ReleaseSource for GitHub {
+    // Read a page at a time, in the order GitHub lists them
+    fn releases(&self, channel: Channel, limit: usize) -> Result<Vec<Release>, Error> {
+        let mut releases = Vec::new();
+        for page in 1.. {
+            let batch: Vec<Release> = self.get(&format!("releases?per_page={}&page={page}", self.page_size))?;
+            let last_page = batch.len() < self.page_size;
+            releases.extend(batch.into_iter().filter(|release| release.is_listed(channel)));
+            if last_page || releases.len() >= limit {
+                break;
+            }
+        }
+        releases.truncate(limit);
+        Ok(releases)
+    }
+
+    fn release(&self, tag: &str) -> Result<Release, Error> {
+        self.get(&format!("releases/tags/{tag}"))
+    }
+
+    fn download(&self, asset: &Asset) -> Result<Vec<u8>, Error> {
+        Ok(self
+            .client
+            .get(&asset.browser_download_url)
+            .header(reqwest::header::USER_AGENT, "mia_cli")
+            .send()?
+            .error_for_status()?
+            .bytes()?
+            .to_vec())
+    }
+
+    fn latest(&self, channel: Channel) -> Result<Release, Error> {
+        match channel {
+            // Never a prerelease or a draft
+            Channel::Stable => self.get("releases/latest"),
+            Channel::Prerelease => newest(self.releases(channel, usize::MAX)?),
+        }
+    }
+}
+
+// A JSON list of releases shaped like GitHub's, with asset URLs relative to the index (or just
+// the asset names) so a mirror is a plain folder
+pub struct Index {
+    index: Url,
+    client: Client,
+}
+
+impl Index {
+    pub fn new(index: &str, client: Client) -> Result<Self, Error> {
+        let index = Url::parse(index).map_err(|error| Error::Custom(format!("Invalid index URL {index}: {error}")))?;
+        Ok(Index { index, client })
+    }
+
+    fn fetch(&self, url: &Url) -> Result<Vec<u8>, Error> {
+        if url.scheme() == "file" {
+            let path: PathBuf = url
+                .to_file_path()
+                .map_err(|_| Error::Custom(format!("Invalid file URL {url}")))?;
+            return Ok(fs::read(path)?);
+        }
+        Ok(self
+            .client
+            .get(url.clone())
+            .header(reqwest::header::USER_AGENT, "mia_cli")
+            .send()?
+            .error_for_status()?
+            .bytes()?
+            .to_vec())
+    }
+}
+
+impl ReleaseSource for Index {
+    // Sorted by semver, whatever the order of the index
+    fn releases(&self, channel: Channel, limit: usize) -> Result<Vec<Release>, Error> {
+        let releases: Vec<Release> = serde_json::from_slice(&self.fetch(&self.index)?)?;
+        let mut releases: Vec<Release> = releases.into_iter().filter(|release| release.is_listed(channel)).collect();
+        releases.sort_by_key(|release| std::cmp::Reverse(release.version()));
+        releases.truncate(limit);
+        Ok(releases)
+    }
+
+    // `1.2.0` finds the `v1.2.0` tag and the other way around
+    fn release(&self, tag: &str) -> Result<Release, Error> {
+        let version = parse_version(tag);
+        self.releases(Channel::Prerelease, usize::MAX)?
+            .into_iter()
+            .find(|release| release.tag_name == tag || (version.is_some() && release.version() == version))
+            .ok_or_else(|| Error::Custom(format!("Release {tag} isn't in {}", self.index)))
+    }
+
+    fn download(&self, asset: &Asset) -> Result<Vec<u8>, Error> {
+        let url = match asset.browser_download_url.as_str() {
+            "" => &asset.name,
+            url => url,
+        };
+        let url = self
+            .index
+            .join(url)
+            .map_err(|error| Error::Custom(format!("Invalid URL for {}: {error}", asset.name)))?;
+        self.fetch(&url)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+    use std::thread;
+
+    // Serves the files of `dir`, a stand-in for a mirror's web server
+    fn serve(dir: &Path) -> String {
+        let server = tiny_http::Server::http("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", server.server_addr().to_ip().unwrap());
+        let dir = dir.to_path_buf();
+        thread::spawn(move || {
+            for request in server.incoming_requests() {
+                let response = match fs::read(dir.join(request.url().trim_start_matches("/mirror/"))) {
+                    Ok(content) => tiny_http::Response::from_data(content),
+                    Err(_) => tiny_http::Response::from_data(b"Not Found".to_vec()).with_status_code(404),
+                };
+                let _ = request.respond(response);
+            }
+        });
+        url
+    }
+
+    fn mirror(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("mia-source-{}-{name}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("assets")).unwrap();
+        fs::write(dir.join("assets/mia-1.1.0"), b"version 1.1.0").unwrap();
+        fs::write(dir.join("assets/mia-1.10.0"), b"version 1.10.0").unwrap();
+        let index = serde_json::json!([
+            { "tag_name": "v1.10.0", "assets": [{ "name": "mia-1.10.0", "browser_download_url": "assets/mia-1.10.0" }] },
+            { "tag_name": "v2.0.0-beta.1", "prerelease": true, "assets": [] },
+            { "tag_name": "v3.0.0", "draft": true, "assets": [] },
+            { "tag_name": "v1.1.0", "body": "First", "assets": [{ "name": "assets/mia-1.1.0" }] },
+        ]);
+        fs::write(dir.join(INDEX_FILE), serde_json::to_vec(&index).unwrap()).unwrap();
+        dir
+    }
+
+    fn tags(releases: Vec<Release>) -> Vec<String> {
+        releases.into_iter().map(|release| release.tag_name).collect()
+    }
+
+    fn check_index(source: &dyn ReleaseSource) {
+        assert_eq!(tags(source.releases(Channel::Stable, usize::MAX).unwrap()), ["v1.10.0", "v1.1.0"]);
+        assert_eq!(tags(source.releases(Channel::Prerelease, 2).unwrap()), ["v2.0.0-beta.1", "v1.10.0"]);
+        assert_eq!(source.latest(Channel::Stable).unwrap().tag_name, "v1.10.0");
+        assert_eq!(source.latest(Channel::Prerelease).unwrap().tag_name, "v2.0.0-beta.1");
+
+        let release = source.release("1.1.0").unwrap();
+        assert_eq!(release.body.as_deref(), Some("First"));
+        assert_eq!(source.download(&release.assets[0]).unwrap(), b"version 1.1.0");
+        let release = source.release("v1.10.0").unwrap();
+        assert_eq!(source.download(&release.assets[0]).unwrap(), b"version 1.10.0");
+        assert!(source.release("v3.0.0").is_err());
+    }
+
+    #[test]
+    fn reads_an_index_from_a_folder() {
+        let dir = mirror("file");
+        let source = open(Some(Url::from_directory_path(&dir).unwrap().as_str()), Client::new()).unwrap();
+        check_index(source.as_ref());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn reads_an_index_over_http() {
+        let dir = mirror("http");
+        let url = serve(&dir);
+        let client = Client::builder().no_proxy().build().unwrap();
+        let source = Index::new(&format!("{url}/mirror/{INDEX_FILE}"), client).unwrap();
+        check_index(&source);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn parses_release_sources() {
+        let github = |url: &str| Some(SourceKind::GitHub(url.to_string()));
+        let index = |url: &str| Some(SourceKind::Index(url.to_string()));
+        assert_eq!(parse(DEFAULT_SOURCE).ok(), github("https://api.github.com/repos/Azuyamat/mia_rust"));
+        assert_eq!(parse("github:ghe.example.com/team/mia").ok(), github("https://ghe.example.com/api/v3/repos/team/mia"));
+        assert_eq!(parse("github:http://127.0.0.1:8080/team/mia/").ok(), github("http://127.0.0.1:8080/api/v3/repos/team/mia"));
+        assert_eq!(parse("https://mirror.example.com/mia").ok(), index("https://mirror.example.com/mia/releases.json"));
+        assert_eq!(parse("https://mirror.example.com/mia/").ok(), index("https://mirror.example.com/mia/releases.json"));
+        assert_eq!(parse("http://mirror/mia/index.json").ok(), index("http://mirror/mia/index.json"));
+        assert_eq!(parse("file:///srv/mia").ok(), index("file:///srv/mia/releases.json"));
+
+        for invalid in ["github:mia", "github:a/b/c/d", "github:team//mia", "ftp://mirror/mia", "/srv/mia", ""] {
+            assert!(parse(invalid).is_err(), "{invalid}");
+        }
+    }
+}
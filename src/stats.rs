@@ -0,0 +1,199 @@
+// Codebase Statistics
+
+use crate::config::Config;
+use crate::dir_manager::{WalkEvent, Walker};
+use crate::error::Error;
+use crate::ignore_files::IgnoreStack;
+use crate::languages::{Language, Languages, LineStats};
+use crate::patterns::Filters;
+use inline_colorization::*;
+use rayon::prelude::*;
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+// What `mia stats` knows about a single file
+pub struct FileStats {
+    // Relative to the walked folder
+    pub path: PathBuf,
+    pub size: u64,
+    pub language: Language,
+    pub lines: LineStats,
+    pub generated: bool,
+}
+
+#[derive(Default)]
+struct Totals {
+    files: u64,
+    size: u64,
+    lines: LineStats,
+}
+
+impl Totals {
+    fn add(&mut self, file: &FileStats) {
+        self.files += 1;
+        self.size += file.size;
+        self.lines += file.lines;
+    }
+}
+
+// Same walk and filtering as `mia create`, without writing an archive
+pub fn stats_directory(
+    location: &Path,
+    config: &Config,
+    exclude: &[String],
+    include: &[String],
+    top: usize,
+    depth: usize,
+) -> Result<(), Error> {
+    if !location.exists() {
+        return Err(Error::PathNotFound);
+    }
+    if !location.is_dir() {
+        return Err(Error::PathNotDir);
+    }
+
+    let start = Instant::now();
+    let filters = Filters::new(location, config, exclude, include)?;
+    let languages = Languages::load(Some(location), config.detect_modelines)?;
+    let walker = Walker::new(location, &filters, config);
+    let events = walker.walk(location, &IgnoreStack::new(config.use_ignore_files))?;
+    let files = events
+        .par_iter()
+        .filter_map(|event| match event {
+            WalkEvent::File(mia_file) => Some(mia_file.stats(&languages)),
+            _ => None,
+        })
+        .collect::<Result<Vec<_>, Error>>()?;
+    let elapsed = start.elapsed().as_millis();
+
+    print_languages(&files);
+    print_tree(&files, depth);
+    print_largest(&files, top);
+    let total_size: u64 = files.iter().map(|file| file.size).sum();
+    println!(
+        "Scanned {color_cyan}{}{color_reset} files ({color_cyan}{}{color_reset}) in {color_cyan}{elapsed}ms{color_reset}",
+        files.len(),
+        format_size(total_size)
+    );
+    Ok(())
+}
+
+fn print_languages(files: &[FileStats]) {
+    let mut languages: HashMap<Language, Totals> = HashMap::new();
+    let mut generated = Totals::default();
+    for file in files {
+        if file.generated {
+            generated.add(file);
+        } else {
+            languages.entry(file.language.clone()).or_default().add(file);
+        }
+    }
+    let mut rows: Vec<(Language, Totals)> = languages.into_iter().collect();
+    // Biggest first, files without a known language last
+    rows.sort_by_key(|(language, totals)| (language.is_other(), -totals.lines.lines()));
+
+    println!("--------------------------------------");
+    println!(
+        "{:<12} {:>8} {:>10} {:>10} {:>10} {:>10}",
+        "Language", "Files", "Code", "Comments", "Blanks", "Size"
+    );
+    let mut total = Totals::default();
+    for (language, totals) in &rows {
+        let name = language.to_string();
+        println!(
+            "{color_cyan}{name:<12}{color_reset} {:>8} {:>10} {:>10} {:>10} {:>10}",
+            totals.files,
+            totals.lines.code,
+            totals.lines.comments,
+            totals.lines.blanks,
+            format_size(totals.size)
+        );
+        total.files += totals.files;
+        total.size += totals.size;
+        total.lines += totals.lines;
+    }
+    if generated.files > 0 {
+        println!(
+            "{color_yellow}{:<12}{color_reset} {:>8} {:>10} {:>10} {:>10} {:>10}",
+            "Generated",
+            generated.files,
+            generated.lines.code,
+            generated.lines.comments,
+            generated.lines.blanks,
+            format_size(generated.size)
+        );
+        total.files += generated.files;
+        total.size += generated.size;
+        total.lines += generated.lines;
+    }
+    println!(
+        "{:<12} {:>8} {:>10} {:>10} {:>10} {:>10}",
+        "Total",
+        total.files,
+        total.lines.code,
+        total.lines.comments,
+        total.lines.blanks,
+        format_size(total.size)
+    );
+}
+
+// Folders up to `depth` levels below the root, each with the totals of everything inside it
+fn print_tree(files: &[FileStats], depth: usize) {
+    // Ordered by components, so every folder comes right before its content
+    let mut folders: BTreeMap<PathBuf, Totals> = BTreeMap::new();
+    for file in files {
+        let Some(parent) = file.path.parent() else { continue };
+        for folder in parent.ancestors() {
+            folders.entry(folder.to_path_buf()).or_default().add(file);
+        }
+    }
+
+    println!("--------------------------------------");
+    for (folder, totals) in &folders {
+        let level = folder.components().count();
+        if level > depth {
+            continue;
+        }
+        let name = match folder.file_name() {
+            Some(name) => format!("{}/", name.to_string_lossy()),
+            None => "./".to_string(),
+        };
+        println!(
+            "{}{color_cyan}{name}{color_reset} {} files, {} lines, {}",
+            "  ".repeat(level),
+            totals.files,
+            totals.lines.lines(),
+            format_size(totals.size)
+        );
+    }
+}
+
+fn print_largest(files: &[FileStats], top: usize) {
+    if top == 0 {
+        return;
+    }
+    let mut largest: Vec<&FileStats> = files.iter().collect();
+    largest.sort_by(|a, b| b.size.cmp(&a.size).then_with(|| a.path.cmp(&b.path)));
+
+    println!("--------------------------------------");
+    println!("Largest files:");
+    for file in largest.into_iter().take(top) {
+        println!("{:>10} {color_cyan}{}{color_reset}", format_size(file.size), file.path.display());
+    }
+    println!("--------------------------------------");
+}
+
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KB", "MB", "GB", "TB"];
+    if bytes < 1024 {
+        return format!("{bytes} B");
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{size:.1} {}", UNITS[unit])
+}
@@ -0,0 +1,130 @@
+// Archive Verifier
+
+use crate::error::Error;
+use crate::manifest::{hex, Manifest, ManifestFormat};
+use crate::password::{Password, PasswordArgs};
+use inline_colorization::*;
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io;
+use std::io::{Read, Seek};
+use std::path::Path;
+use std::time::Instant;
+use zip::result::{ZipError, ZipResult};
+use zip::read::ZipFile;
+use zip::ZipArchive;
+
+// Reads every entry to the end so CRCs (and AES authentication codes) get checked.
+// Archives with a manifest also get every file's SHA-256 and size compared to it.
+pub fn verify_archive(archive: &Path, password_args: &PasswordArgs) -> Result<(), Error> {
+    if !archive.is_file() {
+        return Err(Error::PathNotFound);
+    }
+    let start = Instant::now();
+    let mut zip = ZipArchive::new(File::open(archive)?)?;
+
+    let password = password_args.read_for(&mut zip)?;
+    let manifest = read_manifest(&mut zip, &password)?;
+    let mut seen = HashSet::new();
+
+    let mut failed = 0;
+    let mut mismatched = 0;
+    for index in 0..zip.len() {
+        let result = entry(&mut zip, index, &password).and_then(|mut entry| {
+            let checked = entry.is_file() && !entry.is_symlink();
+            let mut hasher = Sha256::new();
+            let size = io::copy(&mut entry, &mut hasher)?;
+            Ok((entry.name().to_string(), checked, size, hex(&hasher.finalize())))
+        });
+
+        let (name, checked, size, sha256) = match result {
+            Ok(result) => result,
+            Err(ZipError::InvalidPassword) => return Err("Incorrect password".into()),
+            Err(error) => {
+                failed += 1;
+                let name = zip
+                    .name_for_index(index)
+                    .unwrap_or("<unknown>")
+                    .to_string();
+                println!("[FILE] {color_red}x{color_reset} {color_cyan}{name:?}{color_reset} {error}");
+                continue;
+            }
+        };
+
+        let Some((format, expected)) = &manifest else { continue };
+        if !checked || name == format.file_name() {
+            continue;
+        }
+        seen.insert(name.clone());
+        match expected.get(&name) {
+            None => {
+                mismatched += 1;
+                println!("[FILE] {color_yellow}+{color_reset} {color_cyan}{name:?}{color_reset} extra, not in the manifest");
+            }
+            Some((expected_hash, expected_size)) => {
+                if *expected_hash != sha256 || expected_size.is_some_and(|expected| expected != size) {
+                    mismatched += 1;
+                    println!("[FILE] {color_red}~{color_reset} {color_cyan}{name:?}{color_reset} altered, SHA-256 or size differs from the manifest");
+                }
+            }
+        }
+    }
+
+    if let Some((_, expected)) = &manifest {
+        let mut missing: Vec<&String> = expected.keys().filter(|name| !seen.contains(*name)).collect();
+        missing.sort();
+        for name in missing {
+            mismatched += 1;
+            println!("[FILE] {color_red}-{color_reset} {color_cyan}{name:?}{color_reset} missing, listed in the manifest");
+        }
+    }
+
+    let elapsed = start.elapsed().as_millis();
+    if failed > 0 {
+        return Err(Error::Custom(format!("{failed} of {} entries are corrupt", zip.len())));
+    }
+    if mismatched > 0 {
+        return Err(Error::Custom(format!("{mismatched} entries don't match the manifest")));
+    }
+    let checked = match &manifest {
+        Some((format, _)) => format!(" against {}", format.file_name()),
+        None => String::new(),
+    };
+    println!(
+        "Verified {color_cyan}{}{color_reset} entries{checked} in {color_cyan}{elapsed}ms{color_reset}",
+        zip.len()
+    );
+    Ok(())
+}
+
+fn entry<'a, R: Read + Seek>(
+    zip: &'a mut ZipArchive<R>,
+    index: usize,
+    password: &Option<Password>,
+) -> ZipResult<ZipFile<'a, R>> {
+    match password {
+        Some(password) => zip.by_index_decrypt(index, password.as_str().as_bytes()),
+        None => zip.by_index(index),
+    }
+}
+
+// The expected files of the first manifest found at the archive root, keyed by entry name
+#[allow(clippy::type_complexity)]
+fn read_manifest<R: Read + Seek>(
+    zip: &mut ZipArchive<R>,
+    password: &Option<Password>,
+) -> Result<Option<(ManifestFormat, HashMap<String, (String, Option<u64>)>)>, Error> {
+    for format in [ManifestFormat::Json, ManifestFormat::Sha256sums] {
+        let Some(index) = zip.index_for_name(format.file_name()) else { continue };
+        let mut content = Vec::new();
+        entry(zip, index, password)
+            .map_err(|error| match error {
+                ZipError::InvalidPassword => "Incorrect password".into(),
+                error => Error::ZipFileFail(error),
+            })?
+            .read_to_end(&mut content)?;
+        return Ok(Some((format, Manifest::parse(format, &content)?)));
+    }
+    Ok(None)
+}
@@ -0,0 +1,138 @@
+// Retained Versions Manager
+
+use crate::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+// Next to the config file
+const VERSIONS_DIR: &str = "versions";
+
+// A binary kept by `mia update`, `mia update --rollback` restores the newest one.
+// Kept as `<retired>-mia-<version>`, file times aren't reliable enough to order them (copies can keep the
+// source's time, some file systems round to seconds).
+pub struct InstalledVersion {
+    pub version: String,
+    pub path: PathBuf,
+    // When it was replaced by an update, in milliseconds since the Unix epoch. Always above the previous one.
+    sequence: u64,
+    pub size: u64,
+}
+
+impl InstalledVersion {
+    pub fn retired(&self) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_millis(self.sequence)
+    }
+}
+
+pub struct Versions {
+    dir: PathBuf,
+    // How many binaries are kept, older ones are deleted
+    keep: usize,
+}
+
+impl Versions {
+    pub fn new(keep: usize) -> Result<Self, Error> {
+        let config_file = confy::get_configuration_file_path("mia", None)?;
+        let dir = config_file.parent().ok_or(Error::PathNotFound)?.join(VERSIONS_DIR);
+        Ok(Versions::in_dir(dir, keep))
+    }
+
+    pub fn in_dir(dir: PathBuf, keep: usize) -> Self {
+        Versions { dir, keep }
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    // Copies the binary about to be replaced into the versions dir, then drops the oldest ones
+    pub fn retain(&self, executable: &Path, version: &str) -> Result<(), Error> {
+        if self.keep == 0 {
+            return Ok(());
+        }
+        fs::create_dir_all(&self.dir)?;
+        let existing = self.list()?;
+        let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default().as_millis() as u64;
+        let sequence = existing.first().map_or(now, |newest| now.max(newest.sequence + 1));
+        let path = self.dir.join(file_name(sequence, version));
+        // Copied to a temporary name first so a failed copy never looks like a retained version
+        let staged = self.dir.join(format!(".{}.new", file_name(sequence, version)));
+        fs::copy(executable, &staged)?;
+        fs::rename(&staged, &path)?;
+
+        // The same version retained again only keeps its newest copy
+        for old in existing.iter().filter(|installed| installed.version == version) {
+            fs::remove_file(&old.path)?;
+        }
+        for old in self.list()?.into_iter().skip(self.keep) {
+            fs::remove_file(old.path)?;
+        }
+        Ok(())
+    }
+
+    // Newest first
+    pub fn list(&self) -> Result<Vec<InstalledVersion>, Error> {
+        let mut versions = Vec::new();
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(versions),
+            Err(error) => return Err(error.into()),
+        };
+        for entry in entries {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().to_string();
+            let Some((sequence, version)) = parse_file_name(&name) else { continue };
+            let metadata = entry.metadata()?;
+            if !metadata.is_file() {
+                continue;
+            }
+            versions.push(InstalledVersion {
+                version,
+                path: entry.path(),
+                sequence,
+                size: metadata.len(),
+            });
+        }
+        versions.sort_by_key(|installed| std::cmp::Reverse(installed.sequence));
+        Ok(versions)
+    }
+}
+
+fn file_name(sequence: u64, version: &str) -> String {
+    format!("{sequence}-mia-{version}{}", std::env::consts::EXE_SUFFIX)
+}
+
+fn parse_file_name(file_name: &str) -> Option<(u64, String)> {
+    let (sequence, rest) = file_name.split_once('-')?;
+    let version = rest.strip_prefix("mia-")?;
+    let version = version.strip_suffix(std::env::consts::EXE_SUFFIX).unwrap_or(version);
+    Some((sequence.parse().ok()?, version.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn orders_by_retirement_not_file_times() {
+        let dir = std::env::temp_dir().join(format!("mia-versions-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let executable = dir.join("mia");
+        let versions = Versions::in_dir(dir.join("versions"), 3);
+        for version in ["1.0.0", "2.0.0-beta.1", "2.0.0", "1.0.0"] {
+            fs::write(&executable, version).unwrap();
+            versions.retain(&executable, version).unwrap();
+        }
+        // Copies that all look as old as each other
+        for installed in versions.list().unwrap() {
+            let file = fs::File::options().write(true).open(&installed.path).unwrap();
+            file.set_modified(SystemTime::UNIX_EPOCH).unwrap();
+        }
+
+        let listed: Vec<String> = versions.list().unwrap().into_iter().map(|installed| installed.version).collect();
+        assert_eq!(listed, ["1.0.0", "2.0.0", "2.0.0-beta.1"]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}